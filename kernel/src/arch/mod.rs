@@ -2,6 +2,10 @@
 
 pub mod x86_64;
 pub use x86_64::RegisterStore;
+pub use x86_64::SyscallFrame;
 pub use x86_64::mm;
 pub use x86_64::utils::init;
-pub use x86_64::{disable_irq, enable_external_irq, enable_irq, get_irq_enabled};
+pub use x86_64::{NANOS_PER_TICK, TICKS_PER_SECOND};
+pub use x86_64::{
+    disable_irq, enable_external_irq, enable_irq, get_irq_enabled, restore_irq, wait_for_interrupt,
+};
@@ -2,7 +2,7 @@
 
 use core::{arch::asm, u16};
 
-use crate::{mm::definitions::KERNEL_ISTACK_END, trace};
+use crate::{mm::layout::KERNEL_ISTACK_END, trace};
 
 #[repr(transparent)]
 struct GdtEntry(u64);
@@ -57,7 +57,16 @@ static mut GDT: [GdtEntry; 8] = [
 ];
 
 #[unsafe(link_section = ".ldata")]
-static TSS: TssEntry = TssEntry::new(KERNEL_ISTACK_END as u64);
+static mut TSS: TssEntry = TssEntry::new(KERNEL_ISTACK_END as u64);
+
+// double faults always get a known good stack, whatever rsp was
+#[repr(C, align(16))]
+struct DoubleFaultStack([u8; 16 * 1024]);
+
+static mut DOUBLE_FAULT_STACK: DoubleFaultStack = DoubleFaultStack([0; 16 * 1024]);
+
+// ist index used by the double fault gate, slot 0 in the tss
+pub const DOUBLE_FAULT_IST: u8 = 1;
 
 pub const KERNEL_CODE_DESCRIPTOR: u16 = 1 * 0x08;
 pub const KERNEL_DATA_DESCRIPTOR: u16 = 2 * 0x08;
@@ -74,8 +83,14 @@ struct Gdtr {
 
 pub unsafe fn load_gdt() {
     trace!("Loading GDT...");
-    let tss_base = &TSS as *const TssEntry as u64;
-    let tss_limit = (size_of_val(&TSS) - 1) as u64;
+    #[allow(static_mut_refs)]
+    let (tss_base, tss_limit) = unsafe {
+        TSS.ists[DOUBLE_FAULT_IST as usize - 1] = DOUBLE_FAULT_STACK.0.as_ptr_range().end as u64;
+        (
+            &TSS as *const TssEntry as u64,
+            (size_of_val(&TSS) - 1) as u64,
+        )
+    };
     unsafe {
         GDT[6] =
             GdtEntry((tss_limit & 0xffff) | ((tss_base & 0xffffff) << 16) | 0x0000890000000000u64);
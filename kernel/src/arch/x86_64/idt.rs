@@ -7,11 +7,15 @@ use core::arch::{asm, global_asm, naked_asm};
 use lazy_static::lazy_static;
 
 #[allow(unused_imports)]
-use crate::mm::definitions::KERNEL_ISTACK_END;
+use crate::mm::layout::KERNEL_ISTACK_END;
 
 use crate::{
-    arch::x86_64::int::send_eoi,
-    task::{RegisterStore, TASK_MANAGER, schedule_next_task, task::Task},
+    arch::x86_64::{int::send_eoi, usercopy::search_exception_table},
+    mm::{address_space::FaultFlags, definitions::VirtAddress, layout::KERNEL_REGION_BEGIN},
+    task::{
+        RegisterStore, SIGSEGV, TASK_MANAGER, handle_page_fault, is_kernel_stack_address,
+        kill_current_process, preempt_current_task, running_task_id, task::Task, timer_tick,
+    },
     trace,
 };
 
@@ -61,6 +65,12 @@ impl IdtEntry {
         }
     }
 
+    // switch to the given interrupt stack table entry on entry, whatever the privilege level
+    const fn with_ist(mut self, ist: u8) -> Self {
+        self.options_0 = ist & 0b111;
+        self
+    }
+
     const fn default() -> Self {
         Self {
             offset_low: 0,
@@ -151,12 +161,15 @@ lazy_static! {
             GateType::TrapGate,
             PrivilegeLevel::Ring0,
             true,
-        );
+        )
+        .with_ist(gdt::DOUBLE_FAULT_IST);
 
+        // interrupts stay off while resolving faults, the locks it takes are no irq ones so nobody
+        // can be preempted holding them. kernel-mode faults share the int stack
         idt.page_fault = IdtEntry::new(
             page_fault as u64,
             gdt::KERNEL_CODE_DESCRIPTOR,
-            GateType::TrapGate,
+            GateType::InterruptGate,
             PrivilegeLevel::Ring0,
             true,
         );
@@ -361,26 +374,121 @@ extern "sysv64" fn breakpoint_inner(frame: &InterruptionStackFrame) -> () {
     trace!("Breakpoint reached at {:x}!", frame.rip);
 }
 
-make_interruption_handler!(double_fault => double_fault_inner with_error_code);
+// registers as pushed by begin_irq, followed by what the cpu pushed
+#[derive(Debug)]
+#[repr(C)]
+struct SavedRegisters {
+    rbp: u64,
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    frame: InterruptionStackFrameWithErrorCode,
+}
 
-extern "sysv64" fn double_fault_inner(frame: &InterruptionStackFrameWithErrorCode) -> () {
-    trace!("Double fault at {:x}!", frame.rip);
-    panic!("Double fault at {:x}!", frame.rip);
+// already on the ist stack, never returns, so neither gs nor rsp need restoring
+#[naked]
+unsafe extern "C" fn double_fault() {
+    unsafe {
+        naked_asm!(
+            // same layout as begin_irq, spelled out since this may be emitted before the macro
+            "push rax",
+            "push rbx",
+            "push rcx",
+            "push rdx",
+            "push rsi",
+            "push rdi",
+            "push r8",
+            "push r9",
+            "push r10",
+            "push r11",
+            "push r12",
+            "push r13",
+            "push r14",
+            "push r15",
+            "push rbp",
+            "mov rdi, rsp",
+            "and rsp, -16",
+            "call {0}",
+            "ud2",
+            sym double_fault_inner,
+        );
+    }
+}
+
+extern "sysv64" fn double_fault_inner(regs: &SavedRegisters) -> ! {
+    let addr = read_cr2() as usize;
+    // a fault on a guard page cannot push its frame and escalates to here
+    if is_kernel_stack_address(addr) {
+        trace!(
+            "Kernel stack overflow in task {} at {:x} for accessing {:x}!",
+            running_task_id(),
+            regs.frame.rip,
+            addr
+        );
+        trace!("{:#x?}", regs);
+        panic!(
+            "Kernel stack overflow in task {} at {:x}!",
+            running_task_id(),
+            regs.frame.rip
+        );
+    }
+
+    trace!("Double fault at {:x}!", regs.frame.rip);
+    trace!("{:#x?}", regs);
+    panic!("Double fault at {:x}!", regs.frame.rip);
 }
 
 make_interruption_handler!(page_fault => page_fault_inner with_error_code);
 
-extern "sysv64" fn page_fault_inner(frame: &InterruptionStackFrameWithErrorCode) -> () {
-    trace!(
-        "Page fault at {:x} for accessing {:x}!",
-        frame.rip,
-        read_cr2()
-    );
-    panic!(
-        "Page fault at {:x} for accessing {:x}!",
-        frame.rip,
-        read_cr2()
-    );
+extern "sysv64" fn page_fault_inner(frame: &mut InterruptionStackFrameWithErrorCode) -> () {
+    let addr = read_cr2();
+    let mut fault = FaultFlags::empty();
+    if frame.error_code & 1 != 0 {
+        fault |= FaultFlags::Present;
+    }
+    if frame.error_code & (1 << 1) != 0 {
+        fault |= FaultFlags::Write;
+    }
+    if frame.error_code & (1 << 2) != 0 {
+        fault |= FaultFlags::Usermode;
+    }
+    if frame.error_code & (1 << 4) != 0 {
+        fault |= FaultFlags::Execute;
+    }
+
+    if (addr as usize) < KERNEL_REGION_BEGIN
+        && handle_page_fault(VirtAddress::new(addr as usize), fault)
+    {
+        return;
+    }
+
+    if fault.contains(FaultFlags::Usermode) {
+        trace!(
+            "Segmentation fault at {:x} for accessing {:x}, killing task.",
+            frame.rip, addr
+        );
+        kill_current_process(SIGSEGV)
+    }
+
+    // a copy from or to user memory, let it report the failure
+    if let Some(fixup) = search_exception_table(frame.rip) {
+        frame.rip = fixup;
+        return;
+    }
+
+    trace!("Page fault at {:x} for accessing {:x}!", frame.rip, addr);
+    panic!("Page fault at {:x} for accessing {:x}!", frame.rip, addr);
 }
 
 make_interruption_handler!(general_protection => general_proection_inner with_error_code);
@@ -432,7 +540,7 @@ unsafe extern "C" fn timer() {
             "jmp {2}",
             const KERNEL_ISTACK_END,
             sym restore_timer,
-            sym schedule_next_task,
+            sym preempt_current_task,
             const KERNEL_CODE_DESCRIPTOR
         )
     }
@@ -442,4 +550,5 @@ extern "sysv64" fn restore_timer() {
     unsafe {
         send_eoi(0);
     }
+    timer_tick();
 }
@@ -25,6 +25,24 @@ pub unsafe fn enable_irq() {
     }
 }
 
+// undoes a disable_irq, given what it returned
+#[inline(always)]
+pub unsafe fn restore_irq(enabled: bool) {
+    if enabled {
+        unsafe {
+            enable_irq();
+        }
+    }
+}
+
+// sleeps until the next interrupt, interrupts must be enabled
+#[inline(always)]
+pub fn wait_for_interrupt() {
+    unsafe {
+        asm!("hlt");
+    }
+}
+
 #[inline(always)]
 pub unsafe fn enable_external_irq() {
     unsafe {
@@ -40,6 +58,13 @@ pub unsafe fn set_gsbase(base: u64) {
     }
 }
 
+#[inline(always)]
+pub unsafe fn set_fsbase(base: u64) {
+    unsafe {
+        wrmsr(0xC0000100, base);
+    }
+}
+
 pub unsafe fn init_8259a() {
     trace!("Initializing PIC...");
     unsafe {
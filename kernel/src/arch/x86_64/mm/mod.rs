@@ -1,2 +1,3 @@
 #![allow(dead_code)]
 pub mod page_table;
+pub mod pcid;
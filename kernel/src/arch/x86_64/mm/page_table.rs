@@ -1,16 +1,26 @@
 use core::arch::asm;
 
+use crate::arch::x86_64::utils::{get_current_page_table_frame, has_gigabyte_pages, pat_enabled};
 use crate::mm::{
     definitions::{
-        FRAME_SIZE, Frame, FrameAllocator, FrameRegion, MappingRegion, Page, PageFlags, PageRegion,
+        FRAME_SIZE, Frame, FrameAllocError, FrameAllocator, FrameRegion, MappingRegion, Page,
+        PageFlags, PageRegion, PhysAddress, VirtAddress,
     },
     frame_allocator::FRAME_ALLOCATOR,
+    frame_descriptor::FrameOwner,
     utils::{borrow_from_phys_addr_mut, calculate_pptr_from_phys_addr},
 };
+use crate::trace;
+
+use super::pcid::PcidTag;
+
+// pml4 entries from here on map the kernel half, shared by every address space
+const KERNEL_PML4_BEGIN: usize = 256;
 
 #[derive(Debug)]
 pub struct PageTable {
     pml4t: Frame,
+    pcid: PcidTag,
 }
 
 #[derive(Clone, Copy)]
@@ -57,6 +67,89 @@ impl TableEntry {
         self
     }
 
+    fn get_write_through(&self) -> bool {
+        self.0 & (1u64 << 3) != 0
+    }
+
+    fn set_write_through(mut self, val: bool) -> Self {
+        if val {
+            self.0 |= 1u64 << 3;
+        } else {
+            self.0 &= !(1u64 << 3);
+        }
+        self
+    }
+
+    fn get_cache_disabled(&self) -> bool {
+        self.0 & (1u64 << 4) != 0
+    }
+
+    fn set_cache_disabled(mut self, val: bool) -> Self {
+        if val {
+            self.0 |= 1u64 << 4;
+        } else {
+            self.0 &= !(1u64 << 4);
+        }
+        self
+    }
+
+    fn get_accessed(&self) -> bool {
+        self.0 & (1u64 << 5) != 0
+    }
+
+    // only meaningful in a leaf entry
+    fn get_dirty(&self) -> bool {
+        self.0 & (1u64 << 6) != 0
+    }
+
+    // bit 7 in a 4K entry, bit 12 in a huge one
+    fn get_pat(&self, huge: bool) -> bool {
+        self.0 & (1u64 << if huge { 12 } else { 7 }) != 0
+    }
+
+    fn set_pat(mut self, huge: bool, val: bool) -> Self {
+        let bit = 1u64 << if huge { 12 } else { 7 };
+        if val {
+            self.0 |= bit;
+        } else {
+            self.0 &= !bit;
+        }
+        self
+    }
+
+    // index into the pat, see `init_pat`
+    fn get_memory_type(&self, huge: bool) -> PageFlags {
+        let index = (self.get_pat(huge) as u8) << 2
+            | (self.get_cache_disabled() as u8) << 1
+            | self.get_write_through() as u8;
+        match index {
+            0 => PageFlags::empty(),
+            1 | 5 => PageFlags::WriteThrough,
+            4 if pat_enabled() => PageFlags::WriteCombining,
+            _ => PageFlags::Uncached,
+        }
+    }
+
+    fn set_memory_type(self, flags: PageFlags, huge: bool) -> Self {
+        let (pat, pcd, pwt) = if flags.contains(PageFlags::Uncached) {
+            (false, true, true)
+        } else if flags.contains(PageFlags::WriteCombining) {
+            // without the pat, index 4 is plain write-back
+            if pat_enabled() {
+                (true, false, false)
+            } else {
+                (false, true, true)
+            }
+        } else if flags.contains(PageFlags::WriteThrough) {
+            (false, false, true)
+        } else {
+            (false, false, false)
+        };
+        self.set_pat(huge, pat)
+            .set_cache_disabled(pcd)
+            .set_write_through(pwt)
+    }
+
     fn get_global(&self) -> bool {
         self.0 & (1u64 << 8) != 0
     }
@@ -83,6 +176,20 @@ impl TableEntry {
         self
     }
 
+    // available bit 9
+    fn get_copy_on_write(&self) -> bool {
+        self.0 & (1u64 << 9) != 0
+    }
+
+    fn set_copy_on_write(mut self, val: bool) -> Self {
+        if val {
+            self.0 |= 1u64 << 9;
+        } else {
+            self.0 &= !(1u64 << 9);
+        }
+        self
+    }
+
     fn get_frame(&self) -> Frame {
         Frame::new((self.0 >> 12) as usize & ((1usize << 36) - 1))
     }
@@ -93,10 +200,24 @@ impl TableEntry {
         self
     }
 
+    // the pat bit sits where the lowest frame bit would be
+    fn get_huge_frame(&self) -> Frame {
+        Frame::new(self.get_frame().get_index() & !1)
+    }
+
     fn get_huge(self) -> bool {
         self.0 & (1 << 7) != 0
     }
 
+    fn set_huge(mut self, val: bool) -> Self {
+        if val {
+            self.0 |= 1u64 << 7;
+        } else {
+            self.0 &= !(1u64 << 7);
+        }
+        self
+    }
+
     fn empty() -> Self {
         Self(0)
     }
@@ -110,130 +231,95 @@ impl TableEntry {
 struct TableFrame([TableEntry; 512]);
 
 impl crate::mm::definitions::PageTable for PageTable {
-    fn map(&mut self, region: &MappingRegion, flags: PageFlags) {
-        let MappingRegion {
-            virt_begin,
-            phys_begin,
-            num,
-        } = *region;
-        let virt_end = virt_begin.offset(num as isize);
-        let mut phys = phys_begin;
-        let mut i = virt_begin;
-
-        // in pml4t
-        while i != virt_end {
-            let pml4t = unsafe { borrow_from_phys_addr_mut::<TableFrame>(self.pml4t.into()) };
-
-            // in pdpt
-            let pml4e_idx = Self::get_page_index_4(i);
-            let pml4e = &mut pml4t.0[pml4e_idx];
-            let pdpt = if pml4e.get_frame().is_zero() {
-                let frame = self.alloc_table_frame();
-                *pml4e = pml4e.set_frame(frame);
-                Self::set_flags(pml4e, flags, false);
-                unsafe { borrow_from_phys_addr_mut::<TableFrame>(frame.into()) }
-            } else {
-                unsafe { borrow_from_phys_addr_mut::<TableFrame>(pml4e.get_frame().into()) }
-            };
-
-            while i != virt_end && Self::get_page_index_4(i) == pml4e_idx {
-                // in pdt
-                let pdpe_idx = Self::get_page_index_3(i);
-                let pdpe = &mut pdpt.0[pdpe_idx];
-                let pdt = if pdpe.get_frame().is_zero() {
-                    let frame = self.alloc_table_frame();
-                    *pdpe = pdpe.set_frame(frame);
-                    Self::set_flags(pdpe, flags, false);
-                    unsafe { borrow_from_phys_addr_mut::<TableFrame>(frame.into()) }
-                } else {
-                    unsafe { borrow_from_phys_addr_mut::<TableFrame>(pdpe.get_frame().into()) }
-                };
-                while i != virt_end && Self::get_page_index_3(i) == pdpe_idx {
-                    // in pd
-                    let pde_idx = Self::get_page_index_2(i);
-                    let pde = &mut pdt.0[pde_idx];
-                    let pt = if pde.get_frame().is_zero() {
-                        let frame = self.alloc_table_frame();
-                        *pde = pde.set_frame(frame);
-                        Self::set_flags(pde, flags, false);
-                        unsafe { borrow_from_phys_addr_mut::<TableFrame>(frame.into()) }
-                    } else {
-                        unsafe { borrow_from_phys_addr_mut::<TableFrame>(pde.get_frame().into()) }
-                    };
-                    while i != virt_end && Self::get_page_index_2(i) == pde_idx {
-                        let pte_idx = Self::get_page_index_1(i);
-                        let pte = &mut pt.0[pte_idx];
-                        *pte = pte.set_frame(phys);
-                        Self::set_flags(pte, flags, true);
-
-                        i = i.offset(1);
-                        phys = phys.offset(1);
-                    }
-                }
-            }
-        }
+    fn map(&mut self, region: &MappingRegion, flags: PageFlags) -> Result<(), FrameAllocError> {
+        self.map_from(region, flags).map_err(|(error, end)| {
+            // roll back the part mapped before running out of frames. huge pages in it were
+            // mapped as a whole, so nothing needs splitting
+            let begin = region.virt_begin;
+            self.unmap(&PageRegion::new(begin, end.offset_from(begin) as usize))
+                .expect("Rolling back a mapping splits no huge page.");
+            error
+        })
     }
 
-    fn unmap(&mut self, region: &PageRegion) {
-        // TODO: unmap unused directory
-
+    fn unmap(&mut self, region: &PageRegion) -> Result<(), FrameAllocError> {
         let (begin, end) = (region.start(), region.end());
         let mut i = begin;
 
         let pml4t = unsafe { borrow_from_phys_addr_mut::<TableFrame>(self.pml4t.into()) };
-        while i != end {
+        while i < end {
             let pml4e_idx = Self::get_page_index_4(i);
             let pml4e = &mut pml4t.0[pml4e_idx];
-            if pml4e.is_empty() {
-                i = i.offset(1);
+            if !pml4e.get_present() {
+                i = Self::next_table_page(i, 27);
                 continue;
-            } else {
-                while i != end && Self::get_page_index_4(i) == pml4e_idx {
-                    let pdpt = unsafe {
-                        borrow_from_phys_addr_mut::<TableFrame>(pml4e.get_frame().into())
-                    };
-                    let pdpe_idx = Self::get_page_index_3(i);
-                    let pdpe = &mut pdpt.0[pdpe_idx];
-                    if pdpe.is_empty() {
-                        i = i.offset(1);
-                        continue;
-                    } else {
-                        while i != end && Self::get_page_index_3(i) == pdpe_idx {
-                            let pdt = unsafe {
-                                borrow_from_phys_addr_mut::<TableFrame>(pdpe.get_frame().into())
-                            };
-                            let pde_idx = Self::get_page_index_2(i);
-                            let pde = &mut pdt.0[pde_idx];
-                            if pde.is_empty() {
-                                i = i.offset(1);
-                                continue;
-                            } else {
-                                while i != end && Self::get_page_index_2(i) == pde_idx {
-                                    let pt = unsafe {
-                                        borrow_from_phys_addr_mut::<TableFrame>(
-                                            pde.get_frame().into(),
-                                        )
-                                    };
-                                    let pt_idx = Self::get_page_index_1(i);
-                                    if !pt.0[pt_idx].is_empty() {
-                                        pt.0[pt_idx] = TableEntry::empty();
-                                    }
-                                }
-                            }
-                        }
+            }
+
+            let pdpt = unsafe { borrow_from_phys_addr_mut::<TableFrame>(pml4e.get_frame().into()) };
+            let pdpe = &mut pdpt.0[Self::get_page_index_3(i)];
+            if !pdpe.get_present() {
+                i = Self::next_table_page(i, 18);
+                continue;
+            }
+            if pdpe.get_huge() {
+                if Self::covers_huge(i, end, 18) {
+                    *pdpe = TableEntry::empty();
+                    self.invalidate(i);
+                    i = Self::next_table_page(i, 18);
+                    continue;
+                }
+                self.split_huge(pdpe, i, 18)?;
+            }
+
+            let pdt = unsafe { borrow_from_phys_addr_mut::<TableFrame>(pdpe.get_frame().into()) };
+            let pde = &mut pdt.0[Self::get_page_index_2(i)];
+            if !pde.get_present() {
+                i = Self::next_table_page(i, 9);
+                continue;
+            }
+            if pde.get_huge() {
+                if Self::covers_huge(i, end, 9) {
+                    *pde = TableEntry::empty();
+                    self.invalidate(i);
+                    i = Self::next_table_page(i, 9);
+                    continue;
+                }
+                self.split_huge(pde, i, 9)?;
+            }
+
+            let pt = unsafe { borrow_from_phys_addr_mut::<TableFrame>(pde.get_frame().into()) };
+            let pte = &mut pt.0[Self::get_page_index_1(i)];
+            if pte.get_present() {
+                self.invalidate(i);
+            }
+            *pte = TableEntry::empty();
+            i = i.offset(1);
+
+            // leaving a table, reclaim it and its parents once they are empty. kernel tables are
+            // kept, invlpg only drops the paging-structure caches of the current pcid, and the
+            // other ones could still walk through a freed kernel table
+            if pml4e_idx < KERNEL_PML4_BEGIN
+                && (Self::get_page_index_1(i) == 0 || i == end)
+                && Self::is_table_empty(pt)
+            {
+                Self::free_table_frame(pde);
+                if Self::is_table_empty(pdt) {
+                    Self::free_table_frame(pdpe);
+                    if Self::is_table_empty(pdpt) {
+                        Self::free_table_frame(pml4e);
                     }
                 }
             }
         }
+        Ok(())
     }
 
     #[inline(always)]
     unsafe fn bind(&self) {
         unsafe {
             asm!(
-                "shl rax, 12",
                 "mov cr3, rax",
-                in("rax") self.pml4t.get_index())
+                in("rax") self.cr3())
         };
     }
 
@@ -241,10 +327,9 @@ impl crate::mm::definitions::PageTable for PageTable {
     unsafe fn bind_and_switch_stack(&self, sp: usize) {
         unsafe {
             asm!(
-                "shl rax, 12",
                 "mov cr3, rax",
                 "mov rsp, rcx",
-                in("rax") self.pml4t.get_index(),
+                in("rax") self.cr3(),
                 in("rcx") sp
             )
         }
@@ -269,7 +354,7 @@ impl crate::mm::definitions::PageTable for PageTable {
             return None;
         } else if pdpe.get_huge() {
             return Some(
-                pdpe.get_frame()
+                pdpe.get_huge_frame()
                     .offset((pde_idx * (1 << 9) + pte_idx) as isize),
             );
         }
@@ -279,7 +364,7 @@ impl crate::mm::definitions::PageTable for PageTable {
         if !pde.get_present() {
             return None;
         } else if pde.get_huge() {
-            return Some(pde.get_frame().offset(pte_idx as isize));
+            return Some(pde.get_huge_frame().offset(pte_idx as isize));
         }
 
         let pt = unsafe { borrow_from_phys_addr_mut::<TableFrame>(pde.get_frame().into()) };
@@ -290,11 +375,49 @@ impl crate::mm::definitions::PageTable for PageTable {
             Some(pte.get_frame())
         }
     }
+
+    // also sees huge pages, reported with `Huge` and the frame backing `page` itself
+    fn lookup(&self, page: Page) -> Option<(Frame, PageFlags)> {
+        let (entry, shift) = self.mapping_entry(page)?;
+        let huge = shift != 0;
+        let mut flags = entry.get_memory_type(huge);
+        flags.set(PageFlags::Writable, entry.get_writable());
+        flags.set(PageFlags::Usermode, entry.get_usermode());
+        flags.set(PageFlags::Executable, !entry.get_nonexecutable());
+        flags.set(PageFlags::CopyOnWrite, entry.get_copy_on_write());
+        flags.set(PageFlags::Global, entry.get_global());
+        flags.set(PageFlags::Accessed, entry.get_accessed());
+        flags.set(PageFlags::Dirty, entry.get_dirty());
+        flags.set(PageFlags::Huge, huge);
+        let frame = if huge {
+            entry
+                .get_huge_frame()
+                .offset((page.get_index() & ((1 << shift) - 1)) as isize)
+        } else {
+            entry.get_frame()
+        };
+        Some((frame, flags))
+    }
+
+    fn protect(&mut self, region: &PageRegion, flags: PageFlags) {
+        let mut i = region.start();
+        for _ in 0..region.size() {
+            if let Some(pte) = self.leaf_entry(i) {
+                Self::set_leaf_flags(pte, flags, false);
+                self.invalidate(i);
+            }
+            i = i.offset(1);
+        }
+    }
 }
 
 impl Drop for PageTable {
     fn drop(&mut self) {
-        let (begin, end) = (Page::new(0), Page::new(1usize << 36 - 1));
+        // the kernel half belongs to the master table
+        let (begin, end) = (
+            Page::new(0),
+            Self::index_to_page(&[KERNEL_PML4_BEGIN, 0, 0, 0]),
+        );
         let mut i = begin;
 
         let pml4t = unsafe { borrow_from_phys_addr_mut::<TableFrame>(self.pml4t.into()) };
@@ -308,26 +431,24 @@ impl Drop for PageTable {
                     };
                     let pdpe_idx = Self::get_page_index_3(i);
                     let pdpe = &mut pdpt.0[pdpe_idx];
-                    if pdpe.get_present() {
-                        if !pdpe.get_huge() {
-                            while i < end && Self::get_page_index_3(i) == pdpe_idx {
-                                let pdt = unsafe {
-                                    borrow_from_phys_addr_mut::<TableFrame>(pdpe.get_frame().into())
-                                };
-                                let pde_idx = Self::get_page_index_2(i);
-                                let pde = &mut pdt.0[pde_idx];
-                                // free pt
-                                if pde.get_present() && !pde.get_huge() {
-                                    FRAME_ALLOCATOR
-                                        .lock()
-                                        .free(&FrameRegion::new(pde.get_frame(), 1))
-                                        .unwrap();
-                                }
-                                i = Self::index_to_page(&[pml4e_idx, pdpe_idx, pde_idx + 1, 0]);
+                    if pdpe.get_present() && !pdpe.get_huge() {
+                        while i < end && Self::get_page_index_3(i) == pdpe_idx {
+                            let pdt = unsafe {
+                                borrow_from_phys_addr_mut::<TableFrame>(pdpe.get_frame().into())
+                            };
+                            let pde_idx = Self::get_page_index_2(i);
+                            let pde = &mut pdt.0[pde_idx];
+                            // free pt
+                            if pde.get_present() && !pde.get_huge() {
+                                FRAME_ALLOCATOR
+                                    .lock()
+                                    .free(&FrameRegion::new(pde.get_frame(), 1))
+                                    .unwrap();
                             }
+                            i = Self::index_to_page(&[pml4e_idx, pdpe_idx, pde_idx + 1, 0]);
                         }
 
-                        // free pdt
+                        // free pdt, a huge entry maps data instead
                         FRAME_ALLOCATOR
                             .lock()
                             .free(&FrameRegion::new(pdpe.get_frame(), 1))
@@ -375,13 +496,260 @@ impl PageTable {
         Page::new(idx)
     }
 
-    fn alloc_table_frame(&mut self) -> Frame {
-        // TODO: drop
-        // TODO: no unwrap
-        let frame = FRAME_ALLOCATOR.lock().alloc(1).unwrap().start();
+    // present 4K entry of `page`, if any
+    fn leaf_entry(&self, page: Page) -> Option<&'static mut TableEntry> {
+        let indices = [
+            Self::get_page_index_4(page),
+            Self::get_page_index_3(page),
+            Self::get_page_index_2(page),
+        ];
+        let mut table = unsafe { borrow_from_phys_addr_mut::<TableFrame>(self.pml4t.into()) };
+        for idx in indices {
+            let entry = table.0[idx];
+            if !entry.get_present() || entry.get_huge() {
+                return None;
+            }
+            table = unsafe { borrow_from_phys_addr_mut::<TableFrame>(entry.get_frame().into()) };
+        }
+
+        let pte = &mut table.0[Self::get_page_index_1(page)];
+        if pte.get_present() { Some(pte) } else { None }
+    }
+
+    // entry mapping `page` at whatever level, with the number of page bits it covers
+    fn mapping_entry(&self, page: Page) -> Option<(TableEntry, usize)> {
+        let indices = [
+            (Self::get_page_index_4(page), 27),
+            (Self::get_page_index_3(page), 18),
+            (Self::get_page_index_2(page), 9),
+        ];
+        let mut table = unsafe { borrow_from_phys_addr_mut::<TableFrame>(self.pml4t.into()) };
+        for (idx, shift) in indices {
+            let entry = table.0[idx];
+            if !entry.get_present() {
+                return None;
+            }
+            if entry.get_huge() {
+                return Some((entry, shift));
+            }
+            table = unsafe { borrow_from_phys_addr_mut::<TableFrame>(entry.get_frame().into()) };
+        }
+
+        let pte = table.0[Self::get_page_index_1(page)];
+        if pte.get_present() {
+            Some((pte, 0))
+        } else {
+            None
+        }
+    }
+
+    // first page covered by the next entry of the table level selected by `shift`
+    fn next_table_page(page: Page, shift: usize) -> Page {
+        Page::new((page.get_index() | ((1 << shift) - 1)) + 1)
+    }
+
+    // whether [page, end) can start with a huge page of 2^shift pages in `entry`
+    fn fits_huge(
+        flags: PageFlags,
+        page: Page,
+        frame: Frame,
+        end: Page,
+        shift: usize,
+        entry: &TableEntry,
+    ) -> bool {
+        let mask = (1 << shift) - 1;
+        flags.contains(PageFlags::Huge)
+            && page.get_index() & mask == 0
+            && frame.get_index() & mask == 0
+            && end.get_index() - page.get_index() > mask
+            && (!entry.get_present() || entry.get_huge())
+    }
+
+    // whether [page, end) covers the whole huge page of 2^shift pages starting at `page`
+    fn covers_huge(page: Page, end: Page, shift: usize) -> bool {
+        let mask = (1 << shift) - 1;
+        page.get_index() & mask == 0 && end.get_index() - page.get_index() > mask
+    }
+
+    // replaces the huge `entry` mapping 2^shift pages around `page` by a table mapping the same
+    // frames with the same flags one level down, so that a part of it can be changed
+    fn split_huge(
+        &mut self,
+        entry: &mut TableEntry,
+        page: Page,
+        shift: usize,
+    ) -> Result<(), FrameAllocError> {
+        let frame = self.alloc_table_frame()?;
+        let table = unsafe { borrow_from_phys_addr_mut::<TableFrame>(frame.into()) };
+        let base = entry.get_huge_frame();
+        let pat = entry.get_pat(true);
+        // 2M pages below a 1G one, 4K pages below a 2M one
+        let huge = shift > 9;
+        let step = 1 << (shift - 9);
+        for (idx, x) in table.0.iter_mut().enumerate() {
+            *x = entry
+                .set_frame(base.offset((idx * step) as isize))
+                .set_huge(huge)
+                .set_pat(huge, pat);
+        }
+
+        *entry = TableEntry::empty().set_frame(frame);
+        Self::set_flags(entry, PageFlags::empty(), false);
+        self.invalidate(page);
+        Ok(())
+    }
+
+    fn set_huge_entry(&self, entry: &mut TableEntry, page: Page, frame: Frame, flags: PageFlags) {
+        let remapped = entry.get_present();
+        *entry = entry.set_frame(frame).set_huge(true);
+        Self::set_leaf_flags(entry, flags, true);
+        if remapped {
+            self.invalidate(page);
+        }
+    }
+
+    fn is_table_empty(table: &TableFrame) -> bool {
+        table.0.iter().all(|x| x.is_empty())
+    }
+
+    fn free_table_frame(entry: &mut TableEntry) {
+        FRAME_ALLOCATOR
+            .lock()
+            .free(&FrameRegion::new(entry.get_frame(), 1))
+            .unwrap();
+        *entry = TableEntry::empty();
+    }
+
+    // the kernel half is shared and mapped global, so invlpg on whatever table is bound reaches it,
+    // its tables are never freed (see `unmap`). user pages of a table that is not bound may still
+    // be cached under its pcid, flush them on its next bind
+    fn invalidate(&self, page: Page) {
+        let addr: VirtAddress = page.into();
+        let kernel = Self::get_page_index_4(page) >= KERNEL_PML4_BEGIN;
+        if kernel || self.is_bound() {
+            unsafe {
+                asm!("invlpg [{}]", in(reg) addr.as_usize());
+            }
+        } else {
+            self.pcid.mark_stale();
+        }
+    }
+
+    fn is_bound(&self) -> bool {
+        unsafe { get_current_page_table_frame() == self.pml4t }
+    }
+
+    // bit 63 keeps the tlb entries tagged with the pcid
+    fn cr3(&self) -> usize {
+        let (pcid, keep) = self.pcid.activate();
+        (self.pml4t.get_index() << 12) | pcid | ((keep as usize) << 63)
+    }
+
+    // on failure, also returns the first page that was not mapped
+    fn map_from(
+        &mut self,
+        region: &MappingRegion,
+        flags: PageFlags,
+    ) -> Result<(), (FrameAllocError, Page)> {
+        let MappingRegion {
+            virt_begin,
+            phys_begin,
+            num,
+        } = *region;
+        let virt_end = virt_begin.offset(num as isize);
+        let mut phys = phys_begin;
+        let mut i = virt_begin;
+
+        // in pml4t
+        while i != virt_end {
+            let pml4t = unsafe { borrow_from_phys_addr_mut::<TableFrame>(self.pml4t.into()) };
+
+            // in pdpt
+            let pml4e_idx = Self::get_page_index_4(i);
+            let pml4e = &mut pml4t.0[pml4e_idx];
+            let pdpt = if pml4e.get_frame().is_zero() {
+                let frame = self.alloc_table_frame().map_err(|x| (x, i))?;
+                *pml4e = pml4e.set_frame(frame);
+                Self::set_flags(pml4e, flags, false);
+                unsafe { borrow_from_phys_addr_mut::<TableFrame>(frame.into()) }
+            } else {
+                unsafe { borrow_from_phys_addr_mut::<TableFrame>(pml4e.get_frame().into()) }
+            };
+
+            while i != virt_end && Self::get_page_index_4(i) == pml4e_idx {
+                // in pdt
+                let pdpe_idx = Self::get_page_index_3(i);
+                let pdpe = &mut pdpt.0[pdpe_idx];
+                if Self::fits_huge(flags, i, phys, virt_end, 18, pdpe) && has_gigabyte_pages() {
+                    self.set_huge_entry(pdpe, i, phys, flags);
+                    i = i.offset(1 << 18);
+                    phys = phys.offset(1 << 18);
+                    continue;
+                }
+                if pdpe.get_huge() {
+                    self.split_huge(pdpe, i, 18).map_err(|x| (x, i))?;
+                }
+                let pdt = if pdpe.get_frame().is_zero() {
+                    let frame = self.alloc_table_frame().map_err(|x| (x, i))?;
+                    *pdpe = pdpe.set_frame(frame);
+                    Self::set_flags(pdpe, flags, false);
+                    unsafe { borrow_from_phys_addr_mut::<TableFrame>(frame.into()) }
+                } else {
+                    unsafe { borrow_from_phys_addr_mut::<TableFrame>(pdpe.get_frame().into()) }
+                };
+                while i != virt_end && Self::get_page_index_3(i) == pdpe_idx {
+                    // in pd
+                    let pde_idx = Self::get_page_index_2(i);
+                    let pde = &mut pdt.0[pde_idx];
+                    if Self::fits_huge(flags, i, phys, virt_end, 9, pde) {
+                        self.set_huge_entry(pde, i, phys, flags);
+                        i = i.offset(1 << 9);
+                        phys = phys.offset(1 << 9);
+                        continue;
+                    }
+                    if pde.get_huge() {
+                        self.split_huge(pde, i, 9).map_err(|x| (x, i))?;
+                    }
+                    let pt = if pde.get_frame().is_zero() {
+                        let frame = self.alloc_table_frame().map_err(|x| (x, i))?;
+                        *pde = pde.set_frame(frame);
+                        Self::set_flags(pde, flags, false);
+                        unsafe { borrow_from_phys_addr_mut::<TableFrame>(frame.into()) }
+                    } else {
+                        unsafe { borrow_from_phys_addr_mut::<TableFrame>(pde.get_frame().into()) }
+                    };
+                    while i != virt_end && Self::get_page_index_2(i) == pde_idx {
+                        let pte_idx = Self::get_page_index_1(i);
+                        let pte = &mut pt.0[pte_idx];
+                        let remapped = pte.get_present();
+                        *pte = pte.set_frame(phys);
+                        Self::set_leaf_flags(pte, flags, false);
+                        if remapped {
+                            self.invalidate(i);
+                        }
+
+                        i = i.offset(1);
+                        phys = phys.offset(1);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn alloc_table_frame(&mut self) -> Result<Frame, FrameAllocError> {
+        let frame = FRAME_ALLOCATOR
+            .lock()
+            .alloc_for(1, FrameOwner::PageTable)?
+            .start();
 
         unsafe { borrow_from_phys_addr_mut::<[u8; FRAME_SIZE]>(frame.into()) }.fill(0);
-        frame
+        Ok(frame)
+    }
+
+    fn set_leaf_flags(entry: &mut TableEntry, flags: PageFlags, huge: bool) {
+        Self::set_flags(entry, flags, true);
+        *entry = entry.set_memory_type(flags, huge);
     }
 
     fn set_flags(entry: &mut TableEntry, flags: PageFlags, is_leaf: bool) {
@@ -389,6 +757,8 @@ impl PageTable {
             .set_present(true)
             .set_usermode(flags.contains(PageFlags::Usermode) || !is_leaf)
             .set_writable(flags.contains(PageFlags::Writable) || !is_leaf)
+            .set_copy_on_write(flags.contains(PageFlags::CopyOnWrite) && is_leaf)
+            .set_global(flags.contains(PageFlags::Global) && is_leaf)
             .set_nonexecutable(if !is_leaf {
                 false
             } else if flags.contains(PageFlags::Executable) {
@@ -400,8 +770,11 @@ impl PageTable {
 }
 
 impl PageTable {
-    pub fn new() -> Self {
-        let pml4t = FRAME_ALLOCATOR.lock().alloc(1).unwrap().start();
+    pub fn new() -> Result<Self, FrameAllocError> {
+        let pml4t = FRAME_ALLOCATOR
+            .lock()
+            .alloc_for(1, FrameOwner::PageTable)?
+            .start();
         unsafe {
             core::slice::from_raw_parts_mut(
                 calculate_pptr_from_phys_addr::<u8>(pml4t.into()),
@@ -409,10 +782,67 @@ impl PageTable {
             )
             .fill(0);
         }
-        Self { pml4t }
+        Ok(Self {
+            pml4t,
+            pcid: PcidTag::new(),
+        })
     }
 
     pub fn from(pml4t: Frame) -> Self {
-        Self { pml4t }
+        Self {
+            pml4t,
+            pcid: PcidTag::new(),
+        }
+    }
+
+    // gives every kernel pml4 entry a pdpt, so copies of the entries never go stale
+    pub fn populate_kernel_half(&mut self) -> Result<(), FrameAllocError> {
+        let pml4t = unsafe { borrow_from_phys_addr_mut::<TableFrame>(self.pml4t.into()) };
+        for entry in &mut pml4t.0[KERNEL_PML4_BEGIN..] {
+            if !entry.get_present() {
+                *entry = entry.set_frame(self.alloc_table_frame()?);
+                Self::set_flags(entry, PageFlags::empty(), false);
+            }
+        }
+        Ok(())
+    }
+
+    // one line per mapping in `region`, with its memory type among the flags
+    pub fn dump(&self, region: &PageRegion) {
+        let mut i = region.start();
+        while i < region.end() {
+            match self.mapping_entry(i) {
+                Some((_, shift)) => {
+                    let (frame, flags) =
+                        crate::mm::definitions::PageTable::lookup(self, i).unwrap();
+                    let virt: VirtAddress = i.into();
+                    let phys: PhysAddress = frame.into();
+                    trace!(
+                        "{:#x} -> {:#x} ({} pages) {:?}",
+                        virt.as_usize(),
+                        phys.as_usize(),
+                        1usize << shift,
+                        flags
+                    );
+                    i = Self::next_table_page(i, shift);
+                }
+                None => i = i.offset(1),
+            }
+        }
+    }
+
+    pub fn share_kernel_half(&mut self, other: &PageTable) {
+        let src = unsafe { borrow_from_phys_addr_mut::<TableFrame>(other.pml4t.into()) };
+        let dst = unsafe { borrow_from_phys_addr_mut::<TableFrame>(self.pml4t.into()) };
+        dst.0[KERNEL_PML4_BEGIN..].copy_from_slice(&src.0[KERNEL_PML4_BEGIN..]);
+    }
+
+    // copies the top-level entries covering `region`, so both tables share everything below
+    pub fn share_from(&mut self, other: &PageTable, region: &PageRegion) {
+        let begin = Self::get_page_index_4(region.start());
+        let end = Self::get_page_index_4(region.start().offset(region.size() as isize - 1));
+        let src = unsafe { borrow_from_phys_addr_mut::<TableFrame>(other.pml4t.into()) };
+        let dst = unsafe { borrow_from_phys_addr_mut::<TableFrame>(self.pml4t.into()) };
+        dst.0[begin..=end].copy_from_slice(&src.0[begin..=end]);
     }
 }
@@ -0,0 +1,83 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::{
+    arch::x86_64::utils::{has_pcid, read_cr4, write_cr4},
+    sync::SpinLock,
+    trace,
+};
+
+// 12 bits in cr3, pcid 0 is left to tables that never got one
+const PCID_COUNT: usize = 1 << 12;
+const CR4_PCIDE: u64 = 1 << 17;
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+
+struct PcidAllocator {
+    // bumped every time a pcid changes hands, owners holding an older one lost it
+    generations: [u64; PCID_COUNT],
+    next: usize,
+}
+
+static PCID_ALLOCATOR: SpinLock<PcidAllocator> = SpinLock::new(PcidAllocator {
+    generations: [0; PCID_COUNT],
+    next: 1,
+});
+
+// the pcid an address space got last time it was bound, and in which generation
+#[derive(Debug)]
+pub struct PcidTag {
+    pcid: AtomicUsize,
+    generation: AtomicU64,
+    // changed while not bound, its cached entries cannot be trusted
+    stale: AtomicBool,
+}
+
+impl PcidTag {
+    pub const fn new() -> Self {
+        Self {
+            pcid: AtomicUsize::new(0),
+            generation: AtomicU64::new(0),
+            stale: AtomicBool::new(false),
+        }
+    }
+
+    // pcid to load into cr3, and whether the tlb entries tagged with it may be kept
+    pub fn activate(&self) -> (usize, bool) {
+        if !PCID_ENABLED.load(Ordering::Relaxed) {
+            return (0, false);
+        }
+
+        let mut allocator = PCID_ALLOCATOR.lock();
+        let stale = self.stale.swap(false, Ordering::Relaxed);
+        let pcid = self.pcid.load(Ordering::Relaxed);
+        if pcid != 0 && allocator.generations[pcid] == self.generation.load(Ordering::Relaxed) {
+            return (pcid, !stale);
+        }
+
+        // round robin, whatever the previous owner left behind gets flushed by this bind
+        let pcid = allocator.next;
+        allocator.next = if pcid + 1 == PCID_COUNT { 1 } else { pcid + 1 };
+        allocator.generations[pcid] += 1;
+        self.pcid.store(pcid, Ordering::Relaxed);
+        self.generation
+            .store(allocator.generations[pcid], Ordering::Relaxed);
+        (pcid, false)
+    }
+
+    pub fn mark_stale(&self) {
+        self.stale.store(true, Ordering::Relaxed);
+    }
+}
+
+// must run while cr3 still holds pcid 0
+pub unsafe fn init_pcid() {
+    if !has_pcid() {
+        trace!("PCID not supported, every switch flushes the TLB.");
+        return;
+    }
+    unsafe {
+        write_cr4(read_cr4() | CR4_PCIDE);
+    }
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+    trace!("PCID enabled.");
+}
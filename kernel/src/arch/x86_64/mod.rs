@@ -9,6 +9,7 @@ pub mod serial;
 mod syscall;
 pub mod task;
 mod timer;
+pub mod usercopy;
 pub mod utils;
 
 pub use gdt::{
@@ -16,8 +17,12 @@ pub use gdt::{
     load_gdt,
 };
 
-pub use int::{disable_irq, enable_external_irq, enable_irq, get_irq_enabled};
+pub use int::{
+    disable_irq, enable_external_irq, enable_irq, get_irq_enabled, restore_irq, wait_for_interrupt,
+};
 
 pub use idt::load_idt;
 
+pub use syscall::SyscallFrame;
 pub use task::RegisterStore;
+pub use timer::{NANOS_PER_TICK, TICKS_PER_SECOND};
@@ -35,6 +35,7 @@ global_asm!(
     "swapgs",
     "mov gs:0x98, rsp",
     "mov rsp, gs:0x80",
+    "push qword ptr gs:0x98",
     "push r9",
     "push r8",
     "push r10",
@@ -42,7 +43,6 @@ global_asm!(
     "push rsi",
     "push rdi",
     "push rax",
-    "mov rdi, rsp",
     "push rbx",
     "push rcx",
     "push r11",
@@ -50,10 +50,13 @@ global_asm!(
     "push r13",
     "push r14",
     "push r15",
+    "push rbp",
+    "mov rdi, rsp",
     "sti",
     ".endmacro",
     ".macro end_syscall",
     "cli",
+    "pop rbp",
     "pop r15",
     "pop r14",
     "pop r13",
@@ -85,11 +88,24 @@ global_asm!(
     sym handle_syscall_inner
 );
 
-extern "sysv64" fn handle_syscall_inner(parameters: &[u64; 7]) -> usize {
-    unsafe {
-        crate::user::handle_syscall(
-            parameters[0] as usize,
-            core::slice::from_raw_parts((&parameters[1..]).as_ptr() as *const usize, 6),
-        )
-    }
+// layout pushed by start_syscall
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub rbp: u64,
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // rdi, rsi, rdx, r10, r8, r9
+    pub parameters: [usize; 6],
+    pub user_rsp: u64,
+}
+
+extern "sysv64" fn handle_syscall_inner(frame: &SyscallFrame) -> usize {
+    crate::user::handle_syscall(frame.rax as usize, &frame.parameters, frame)
 }
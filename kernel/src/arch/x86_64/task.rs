@@ -8,11 +8,14 @@ use crate::{
         KERNEL_CODE_DESCRIPTOR, USER_CODE_DESCRIPTOR,
         gdt::{KERNEL_DATA_DESCRIPTOR, USER_DATA_DESCRIPTOR},
     },
-    mm::definitions::KERNEL_REGION_BEGIN,
+    mm::layout::{KERNEL_ISTACK_END, KERNEL_REGION_BEGIN},
     trace,
 };
 
-use super::int::set_gsbase;
+use super::{
+    int::{set_fsbase, set_gsbase},
+    syscall::SyscallFrame,
+};
 
 // Sysv64: Functions preserve the registers rbx, rsp, rbp, r12, r13, r14, and r15; while rax, rdi, rsi, rdx, rcx, r8, r9, r10, r11 are scratch registers.
 #[repr(C)]
@@ -102,6 +105,76 @@ impl crate::task::RegisterStore for RegisterStore {
         result.rflags = 0x200;
         result
     }
+
+    fn set_return_value(&mut self, value: usize) {
+        self.rax = value as u64;
+    }
+
+    fn set_argument(&mut self, value: usize) {
+        self.rdi = value as u64;
+    }
+}
+
+impl RegisterStore {
+    // user state at the point the syscall was made, resuming after the syscall instruction
+    pub fn from_syscall(frame: &SyscallFrame, ksp: usize) -> Self {
+        let [rdi, rsi, rdx, r10, r8, r9] = frame.parameters.map(|x| x as u64);
+        Self {
+            rax: frame.rax,
+            rbx: frame.rbx,
+            rcx: frame.rcx,
+            rdx,
+            rsi,
+            rdi,
+            r8,
+            r9,
+            r10,
+            r11: frame.r11,
+            r12: frame.r12,
+            r13: frame.r13,
+            r14: frame.r14,
+            r15: frame.r15,
+            rbp: frame.rbp,
+            rsp: frame.user_rsp,
+            kernel_rsp: ksp as u64,
+            rflags: frame.r11,
+            rip: frame.rcx,
+            user_rsp: frame.user_rsp,
+        }
+    }
+
+    // false for a task switched out in the kernel, which saved its kernel context
+    pub fn in_user_mode(&self) -> bool {
+        self.rip < KERNEL_REGION_BEGIN as u64
+    }
+}
+
+// saves the kernel context of the running task, the one gs points at, and calls `next` on the
+// int stack. returns when the task is switched to again, with the interrupt flag as it was
+#[naked]
+pub unsafe extern "sysv64" fn switch_out(next: extern "sysv64" fn() -> !) {
+    unsafe {
+        naked_asm!(
+            "pushfq",
+            "cli",
+            "pop rax",
+            "mov gs:0x88, rax",
+            "mov gs:0x08, rbx",
+            "mov gs:0x50, r12",
+            "mov gs:0x58, r13",
+            "mov gs:0x60, r14",
+            "mov gs:0x68, r15",
+            "mov gs:0x70, rbp",
+            "mov gs:0x78, rsp",
+            "lea rax, [rip + 2f]",
+            "mov gs:0x90, rax",
+            "mov rsp, {0}",
+            "call rdi",
+            "2:",
+            "ret",
+            const KERNEL_ISTACK_END,
+        )
+    }
 }
 
 #[inline(always)]
@@ -118,3 +191,11 @@ pub unsafe fn set_structure_base(addr: u64, switch_to_kernel: bool) {
         }
     }
 }
+
+// fs is the thread pointer on x86_64
+#[inline(always)]
+pub unsafe fn set_thread_pointer(addr: u64) {
+    unsafe {
+        set_fsbase(addr);
+    }
+}
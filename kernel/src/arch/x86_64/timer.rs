@@ -5,10 +5,13 @@ use super::io::out8;
 const PIT_CMD_PORT: u16 = 0x43;
 const PIT_DATA_PORT: u16 = 0x40;
 
+pub const TICKS_PER_SECOND: u64 = 100;
+pub const NANOS_PER_TICK: u64 = 1_000_000_000 / TICKS_PER_SECOND;
+
 pub unsafe fn init_timer() {
     trace!("Initializing timer...");
     let clock_freq = 1193182usize;
-    let expected_freq = 100usize;
+    let expected_freq = TICKS_PER_SECOND as usize;
     let k = (clock_freq / expected_freq) as u16;
     unsafe {
         out8(PIT_CMD_PORT, 0b00110110);
@@ -0,0 +1,80 @@
+use core::arch::global_asm;
+
+// faulting instruction and where to resume instead, emitted next to every user access
+#[repr(C)]
+struct ExceptionEntry {
+    fault: u64,
+    fixup: u64,
+}
+
+unsafe extern "C" {
+    static _ex_table_start: ExceptionEntry;
+    static _ex_table_end: ExceptionEntry;
+
+    // returns how many bytes were left uncopied, 0 on success
+    fn copy_user_raw(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    // returns the length without the nul, `max` if none was found, or -1 on a fault
+    fn strncpy_user_raw(dst: *mut u8, src: *const u8, max: usize) -> isize;
+}
+
+global_asm!(
+    r#"
+    .global copy_user_raw
+    copy_user_raw:
+    mov rcx, rdx
+    .Lcopy_user_access:
+    rep movsb
+    xor eax, eax
+    ret
+    .Lcopy_user_fixup:
+    mov rax, rcx
+    ret
+
+    .global strncpy_user_raw
+    strncpy_user_raw:
+    xor eax, eax
+    .Lstrncpy_user_loop:
+    cmp rax, rdx
+    je .Lstrncpy_user_done
+    .Lstrncpy_user_access:
+    mov cl, byte ptr [rsi + rax]
+    mov byte ptr [rdi + rax], cl
+    test cl, cl
+    jz .Lstrncpy_user_done
+    inc rax
+    jmp .Lstrncpy_user_loop
+    .Lstrncpy_user_done:
+    ret
+    .Lstrncpy_user_fixup:
+    mov rax, -1
+    ret
+
+    .pushsection __ex_table, "a"
+    .quad .Lcopy_user_access, .Lcopy_user_fixup
+    .quad .Lstrncpy_user_access, .Lstrncpy_user_fixup
+    .popsection
+    "#
+);
+
+// the caller checks that the user side lies below the kernel region
+pub unsafe fn copy_user(dst: *mut u8, src: *const u8, len: usize) -> Result<(), usize> {
+    match unsafe { copy_user_raw(dst, src, len) } {
+        0 => Ok(()),
+        left => Err(left),
+    }
+}
+
+pub unsafe fn strncpy_user(dst: *mut u8, src: *const u8, max: usize) -> Option<usize> {
+    let len = unsafe { strncpy_user_raw(dst, src, max) };
+    if len < 0 { None } else { Some(len as usize) }
+}
+
+// where to continue after a kernel fault at `rip`, if it was a user access
+pub fn search_exception_table(rip: u64) -> Option<u64> {
+    let table = unsafe {
+        let begin = &raw const _ex_table_start;
+        let end = &raw const _ex_table_end;
+        core::slice::from_raw_parts(begin, end.offset_from(begin) as usize)
+    };
+    table.iter().find(|x| x.fault == rip).map(|x| x.fixup)
+}
@@ -1,4 +1,7 @@
-use core::arch::asm;
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
 
 use crate::{
     arch::{disable_irq, enable_external_irq},
@@ -7,8 +10,11 @@ use crate::{
 };
 
 use super::{
-    int::init_8259a, load_gdt, load_idt, logging, mm::page_table::PageTable as X86PageTable,
-    syscall::init_syscall, timer::init_timer,
+    int::init_8259a,
+    load_gdt, load_idt, logging,
+    mm::{page_table::PageTable as X86PageTable, pcid::init_pcid},
+    syscall::init_syscall,
+    timer::init_timer,
 };
 
 pub fn init() {
@@ -26,6 +32,9 @@ pub fn init() {
     trace!("Initializing misc...");
     unsafe {
         init_nonexecutable_paging();
+        init_global_pages();
+        init_pat();
+        init_pcid();
         init_syscall();
     }
 }
@@ -63,6 +72,55 @@ unsafe fn init_nonexecutable_paging() {
     }
 }
 
+// cr4.pge, kernel mappings marked global survive cr3 reloads
+unsafe fn init_global_pages() {
+    unsafe {
+        write_cr4(read_cr4() | 0x80);
+    }
+}
+
+const IA32_PAT: u32 = 0x277;
+// entries 0-3 keep their power-on types so pcd/pwt alone mean what they always did,
+// 4 is write-combining: wb, wt, uc-, uc, wc, wt, uc-, uc
+const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+unsafe fn init_pat() {
+    if !has_pat() {
+        trace!("PAT not supported, write-combining falls back to uncached.");
+        return;
+    }
+    unsafe {
+        wrmsr(IA32_PAT, PAT_VALUE);
+    }
+    PAT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn pat_enabled() -> bool {
+    PAT_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn read_cr4() -> u64 {
+    let cr4: u64;
+    unsafe {
+        asm!(
+            "mov rax, cr4",
+            out("rax") cr4
+        );
+    }
+    cr4
+}
+
+pub unsafe fn write_cr4(cr4: u64) {
+    unsafe {
+        asm!(
+            "mov cr4, rax",
+            in("rax") cr4
+        );
+    }
+}
+
 pub unsafe fn get_current_page_table_frame() -> Frame {
     let cr3: usize;
     unsafe {
@@ -73,3 +131,75 @@ pub unsafe fn get_current_page_table_frame() -> Frame {
     }
     Frame::new((cr3 >> 12) & ((1 << 36) - 1))
 }
+
+// cpuid 0x80000001, edx bit 26
+pub fn has_gigabyte_pages() -> bool {
+    unsafe { __cpuid(0x8000_0001).edx & (1 << 26) != 0 }
+}
+
+// cpuid 1, edx bit 16
+pub fn has_pat() -> bool {
+    unsafe { __cpuid(1).edx & (1 << 16) != 0 }
+}
+
+// cpuid 1, ecx bit 17
+pub fn has_pcid() -> bool {
+    unsafe { __cpuid(1).ecx & (1 << 17) != 0 }
+}
+
+// cpuid 1, ecx bit 30
+pub fn has_rdrand() -> bool {
+    unsafe { __cpuid(1).ecx & (1 << 30) != 0 }
+}
+
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!(
+            "rdtsc",
+            out("edx") high,
+            out("eax") low
+        )
+    }
+    ((high as u64) << 32) | (low as u64)
+}
+
+fn rdrand() -> Option<u64> {
+    // the instruction may run dry for a moment, retry a few times
+    for _ in 0..10 {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!(
+                "rdrand {0}",
+                "setc {1}",
+                out(reg) value,
+                out(reg_byte) ok
+            )
+        }
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(0);
+
+// rdrand if the cpu has it, otherwise xorshift seeded from the tsc
+pub fn random_u64() -> u64 {
+    if let Some(value) = has_rdrand().then(rdrand).flatten() {
+        return value;
+    }
+
+    let mut x = RANDOM_STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        x = rdtsc() | 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    RANDOM_STATE.store(x, Ordering::Relaxed);
+    x
+}
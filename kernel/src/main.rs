@@ -23,18 +23,17 @@ use bootloader_api::config::Mapping;
 use bootloader_api::entry_point;
 use bootloader_api::info::MemoryRegionKind;
 use mm::definitions::FRAME_SIZE;
-use mm::definitions::FrameAllocator;
 use mm::definitions::FrameRegion;
-use mm::definitions::PHYSICAL_MAP_BEGIN;
 use mm::definitions::PhysAddress;
 use mm::frame_allocator::FRAME_ALLOCATOR;
+use mm::layout::BOOT_PHYSICAL_MAP_BEGIN;
 use mm::utils::init_mm;
 
-use crate::task::init_first_process_and_jump_to;
+use crate::task::{OomPolicy, init_first_process_and_jump_to, set_oom_policy};
 
 static CONFIG: BootloaderConfig = {
     let mut cfg = BootloaderConfig::new_default();
-    cfg.mappings.physical_memory = Some(Mapping::FixedAddress(PHYSICAL_MAP_BEGIN as u64));
+    cfg.mappings.physical_memory = Some(Mapping::FixedAddress(BOOT_PHYSICAL_MAP_BEGIN as u64));
     cfg
 };
 
@@ -44,21 +43,33 @@ pub static INIT_PROGRAM: &[u8] = include_bytes!("../artifacts/user");
 
 pub fn kernel_boot(boot_info: &'static mut BootInfo) -> ! {
     arch::init();
-    for region in boot_info
+    let regions = boot_info
         .memory_regions
         .iter()
         .filter(|x| x.kind == MemoryRegionKind::Usable)
-    {
-        let frame_begin = PhysAddress::new(region.start as usize + FRAME_SIZE - 1).get_frame();
-        let frame_end = PhysAddress::new(region.end as usize - FRAME_SIZE + 1).get_frame();
-        FRAME_ALLOCATOR
-            .lock()
-            .free(&FrameRegion::new(
+        .map(|region| {
+            let frame_begin = PhysAddress::new(region.start as usize + FRAME_SIZE - 1).get_frame();
+            let frame_end = PhysAddress::new(region.end as usize).get_frame();
+            FrameRegion::new(
                 frame_begin,
-                frame_end.offset_from(frame_begin) as usize - 1,
-            ))
-            .unwrap();
+                frame_end
+                    .get_index()
+                    .saturating_sub(frame_begin.get_index()),
+            )
+        });
+    let phys_end = boot_info
+        .memory_regions
+        .iter()
+        .filter(|x| x.kind == MemoryRegionKind::Usable)
+        .map(|x| x.end as usize)
+        .max()
+        .unwrap_or(0);
+    FRAME_ALLOCATOR.lock().init(regions);
+    init_mm(PhysAddress::new(phys_end));
+    if cfg!(feature = "oom-panic") {
+        set_oom_policy(OomPolicy::Panic);
+    } else if cfg!(feature = "oom-fail") {
+        set_oom_policy(OomPolicy::Fail);
     }
-    init_mm();
     init_first_process_and_jump_to()
 }
@@ -0,0 +1,597 @@
+use alloc::vec::Vec;
+use bitflags::bitflags;
+
+use crate::arch::mm::page_table::PageTable as ArchPageTable;
+
+use super::{
+    definitions::{
+        FRAME_SIZE, Frame, MappingRegion, Page, PageFlags, PageRegion, PageTable, VirtAddress,
+    },
+    frame_allocator::FRAME_ALLOCATOR,
+    frame_descriptor::{FrameOwner, FrameState},
+    layout::{MMAP_BEGIN, USER_REGION_END},
+    utils::borrow_from_phys_addr_mut,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    // zero-filled frames, allocated on first touch
+    Anonymous,
+    // mapped up front, e.g. by the elf loader
+    Populated,
+    // never mapped, any access is a violation
+    Guard,
+}
+
+#[derive(Debug, Clone)]
+pub struct VirtualMemoryArea {
+    region: PageRegion,
+    flags: PageFlags,
+    backing: Backing,
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy)]
+    pub struct FaultFlags: u8 {
+        const Present = 1;
+        const Write = 2;
+        const Usermode = 4;
+        const Execute = 8;
+    }
+}
+
+#[derive(Debug)]
+pub enum AreaError {
+    Overlapping,
+    NoSpace,
+    NotMapped,
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Placement {
+    Anywhere,
+    // used if free, otherwise like `Anywhere`
+    Hint(Page),
+    // replaces whatever is mapped there
+    Fixed(Page),
+}
+
+#[derive(Debug)]
+pub enum FaultError {
+    NoArea,
+    AccessViolation,
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ProgramBreak {
+    base: usize,
+    current: usize,
+}
+
+pub struct AddressSpace {
+    page_table: ArchPageTable,
+    // sorted by start, never overlapping
+    areas: Vec<VirtualMemoryArea>,
+    program_break: ProgramBreak,
+    // frames currently mapped in the user areas
+    resident: usize,
+}
+
+impl VirtualMemoryArea {
+    pub fn new(region: PageRegion, flags: PageFlags, backing: Backing) -> Self {
+        Self {
+            region,
+            flags,
+            backing,
+        }
+    }
+
+    #[inline]
+    pub fn start(&self) -> Page {
+        self.region.start()
+    }
+
+    #[inline]
+    pub fn end(&self) -> Page {
+        self.region.end()
+    }
+
+    #[inline]
+    pub fn flags(&self) -> PageFlags {
+        self.flags
+    }
+
+    #[inline]
+    pub fn backing(&self) -> Backing {
+        self.backing
+    }
+
+    pub fn contains(&self, page: Page) -> bool {
+        self.start() <= page && page < self.end()
+    }
+
+    fn merges_with(&self, next: &Self) -> bool {
+        self.end() == next.start() && self.flags == next.flags && self.backing == next.backing
+    }
+
+    fn extend(&mut self, num: usize) {
+        self.region = PageRegion::new(self.start(), self.region.size() + num);
+    }
+
+    // the part of this area inside [begin, end)
+    fn slice(&self, begin: Page, end: Page) -> Self {
+        let begin = begin.max(self.start());
+        let end = end.min(self.end());
+        Self::new(
+            PageRegion::new(begin, end.offset_from(begin) as usize),
+            self.flags,
+            self.backing,
+        )
+    }
+
+    fn permits(&self, fault: FaultFlags) -> bool {
+        !(self.backing == Backing::Guard
+            || fault.contains(FaultFlags::Write) && !self.flags.contains(PageFlags::Writable)
+            || fault.contains(FaultFlags::Execute) && !self.flags.contains(PageFlags::Executable)
+            || fault.contains(FaultFlags::Usermode) && !self.flags.contains(PageFlags::Usermode))
+    }
+}
+
+impl AddressSpace {
+    pub fn new(page_table: ArchPageTable) -> Self {
+        Self {
+            page_table,
+            areas: Vec::new(),
+            program_break: ProgramBreak {
+                base: 0,
+                current: 0,
+            },
+            resident: 0,
+        }
+    }
+
+    pub fn resident_pages(&self) -> usize {
+        self.resident
+    }
+
+    pub fn page_table(&self) -> &ArchPageTable {
+        &self.page_table
+    }
+
+    pub fn page_table_mut(&mut self) -> &mut ArchPageTable {
+        &mut self.page_table
+    }
+
+    pub fn add_area(&mut self, area: VirtualMemoryArea) -> Result<(), AreaError> {
+        let idx = self.areas.partition_point(|x| x.start() < area.start());
+        let overlaps_prev = idx > 0 && self.areas[idx - 1].end() > area.start();
+        let overlaps_next = idx < self.areas.len() && self.areas[idx].start() < area.end();
+        if overlaps_prev || overlaps_next {
+            return Err(AreaError::Overlapping);
+        }
+
+        self.areas.insert(idx, area);
+        if idx + 1 < self.areas.len() && self.areas[idx].merges_with(&self.areas[idx + 1]) {
+            let next = self.areas.remove(idx + 1);
+            self.areas[idx].extend(next.region.size());
+        }
+        if idx > 0 && self.areas[idx - 1].merges_with(&self.areas[idx]) {
+            let area = self.areas.remove(idx);
+            self.areas[idx - 1].extend(area.region.size());
+        }
+        Ok(())
+    }
+
+    pub fn find_area(&self, page: Page) -> Option<&VirtualMemoryArea> {
+        let idx = self.areas.partition_point(|x| x.end() <= page);
+        self.areas.get(idx).filter(|x| x.contains(page))
+    }
+
+    // records an area whose frames are provided by the caller and maps it right away
+    pub fn map_populated(
+        &mut self,
+        region: &MappingRegion,
+        flags: PageFlags,
+    ) -> Result<(), AreaError> {
+        self.add_area(VirtualMemoryArea::new(
+            PageRegion::new(region.virt_begin, region.num),
+            flags,
+            Backing::Populated,
+        ))?;
+        if self.page_table.map(region, flags).is_err() {
+            // the frames stay with the caller
+            let begin = region.virt_begin;
+            self.take_areas(begin, begin.offset(region.num as isize));
+            return Err(AreaError::OutOfMemory);
+        }
+        self.resident += region.num;
+        Ok(())
+    }
+
+    pub fn map_anonymous(
+        &mut self,
+        placement: Placement,
+        num: usize,
+        flags: PageFlags,
+    ) -> Result<Page, AreaError> {
+        let begin = match placement {
+            Placement::Fixed(page) => {
+                self.unmap(&PageRegion::new(page, num));
+                page
+            }
+            Placement::Hint(page) if self.is_free(&PageRegion::new(page, num)) => page,
+            _ => self.find_free_region(num).ok_or(AreaError::NoSpace)?,
+        };
+
+        self.add_area(VirtualMemoryArea::new(
+            PageRegion::new(begin, num),
+            flags,
+            Backing::Anonymous,
+        ))?;
+        Ok(begin)
+    }
+
+    // copies `data` to `addr` as a user mode write would, also while another address space is
+    // active. pages are faulted in and copied on write as needed
+    pub fn write(&mut self, addr: VirtAddress, data: &[u8]) -> Result<(), FaultError> {
+        let mut written = 0;
+        while written < data.len() {
+            let addr = VirtAddress::new(addr.as_usize() + written);
+            let page = addr.get_page();
+            let fault = match self.page_table.lookup(page) {
+                None => Some(FaultFlags::empty()),
+                Some((_, flags)) if flags.contains(PageFlags::CopyOnWrite) => {
+                    Some(FaultFlags::Present)
+                }
+                Some(_) => None,
+            };
+            if let Some(fault) = fault {
+                self.handle_fault(addr, fault | FaultFlags::Write | FaultFlags::Usermode)?;
+            }
+
+            let (frame, _) = self
+                .page_table
+                .lookup(page)
+                .ok_or(FaultError::AccessViolation)?;
+            let offset = addr.as_usize() % FRAME_SIZE;
+            let size = (FRAME_SIZE - offset).min(data.len() - written);
+            unsafe {
+                let dst = borrow_from_phys_addr_mut::<[u8; FRAME_SIZE]>(frame.into());
+                dst[offset..offset + size].copy_from_slice(&data[written..written + size]);
+            }
+            written += size;
+        }
+        Ok(())
+    }
+
+    // the heap starts right after the loaded program
+    pub fn init_break(&mut self, addr: VirtAddress) {
+        let base = addr.as_usize().next_multiple_of(FRAME_SIZE);
+        self.program_break = ProgramBreak {
+            base,
+            current: base,
+        };
+    }
+
+    pub fn get_break(&self) -> usize {
+        self.program_break.current
+    }
+
+    // moves the break, pages between the old and new end are mapped or unmapped eagerly
+    pub fn set_break(&mut self, addr: usize) -> Result<usize, AreaError> {
+        if addr < self.program_break.base || addr > USER_REGION_END {
+            return Err(AreaError::NoSpace);
+        }
+
+        let old_end =
+            VirtAddress::new(self.program_break.current.next_multiple_of(FRAME_SIZE)).get_page();
+        let new_end = VirtAddress::new(addr.next_multiple_of(FRAME_SIZE)).get_page();
+        if new_end > old_end {
+            let region = PageRegion::new(old_end, new_end.offset_from(old_end) as usize);
+            if !self.is_free(&region) {
+                return Err(AreaError::NoSpace);
+            }
+
+            let flags = PageFlags::Usermode | PageFlags::Writable;
+            self.add_area(VirtualMemoryArea::new(
+                region.clone(),
+                flags,
+                Backing::Anonymous,
+            ))?;
+            let mut page = old_end;
+            while page != new_end {
+                let Ok(frames) = FRAME_ALLOCATOR
+                    .lock()
+                    .alloc_for(1, FrameOwner::UserAnonymous)
+                else {
+                    self.unmap(&region);
+                    return Err(AreaError::OutOfMemory);
+                };
+                unsafe { borrow_from_phys_addr_mut::<[u8; FRAME_SIZE]>(frames.start().into()) }
+                    .fill(0);
+                let mapped = self.page_table.map(
+                    &MappingRegion {
+                        phys_begin: frames.start(),
+                        virt_begin: page,
+                        num: 1,
+                    },
+                    flags,
+                );
+                if mapped.is_err() {
+                    FRAME_ALLOCATOR.lock().release(frames.start()).unwrap();
+                    self.unmap(&region);
+                    return Err(AreaError::OutOfMemory);
+                }
+                self.resident += 1;
+                page = page.offset(1);
+            }
+        } else if new_end < old_end {
+            self.unmap(&PageRegion::new(
+                new_end,
+                old_end.offset_from(new_end) as usize,
+            ));
+        }
+
+        self.program_break.current = addr;
+        Ok(addr)
+    }
+
+    // removes the areas inside `region`, splitting those crossing its bounds
+    pub fn unmap(&mut self, region: &PageRegion) {
+        let (begin, end) = (region.start(), region.end());
+        for area in self.take_areas(begin, end) {
+            let mut page = area.start();
+            while page != area.end() {
+                if let Some((frame, flags)) = self.page_table.lookup(page) {
+                    release_mapped(frame, flags);
+                    self.resident -= 1;
+                }
+                page = page.offset(1);
+            }
+            self.page_table
+                .unmap(&area.region)
+                .expect("User mappings are never huge.");
+        }
+    }
+
+    // changes the protection of `region`, which must be mapped as a whole
+    pub fn protect(&mut self, region: &PageRegion, flags: PageFlags) -> Result<(), AreaError> {
+        let (begin, end) = (region.start(), region.end());
+        let mut covered = begin;
+        for area in self
+            .areas
+            .iter()
+            .filter(|x| x.end() > begin && x.start() < end)
+        {
+            if area.start() > covered {
+                break;
+            }
+            covered = area.end();
+        }
+        if covered < end {
+            return Err(AreaError::NotMapped);
+        }
+
+        for mut area in self.take_areas(begin, end) {
+            area.flags = flags;
+            let mut page = area.start();
+            while page != area.end() {
+                // shared frames stay read-only until copied
+                if let Some((_, current)) = self.page_table.lookup(page) {
+                    let flags = if current.contains(PageFlags::CopyOnWrite) {
+                        (flags - PageFlags::Writable) | PageFlags::CopyOnWrite
+                    } else {
+                        flags
+                    };
+                    self.page_table.protect(&PageRegion::new(page, 1), flags);
+                }
+                page = page.offset(1);
+            }
+            self.add_area(area)?;
+        }
+        Ok(())
+    }
+
+    // cuts [begin, end) out of the area list and returns the removed pieces
+    fn take_areas(&mut self, begin: Page, end: Page) -> Vec<VirtualMemoryArea> {
+        let first = self.areas.partition_point(|x| x.end() <= begin);
+        let last = self.areas.partition_point(|x| x.start() < end);
+        if first >= last {
+            return Vec::new();
+        }
+
+        let removed: Vec<_> = self.areas.drain(first..last).collect();
+        let mut kept = Vec::new();
+        if removed[0].start() < begin {
+            kept.push(removed[0].slice(removed[0].start(), begin));
+        }
+        if removed[removed.len() - 1].end() > end {
+            let last = &removed[removed.len() - 1];
+            kept.push(last.slice(end, last.end()));
+        }
+        for area in kept {
+            self.add_area(area).unwrap();
+        }
+
+        removed.iter().map(|x| x.slice(begin, end)).collect()
+    }
+
+    fn is_free(&self, region: &PageRegion) -> bool {
+        let end = region.end();
+        VirtAddress::new(MMAP_BEGIN).get_page() <= region.start()
+            && end <= VirtAddress::new(USER_REGION_END).get_page()
+            && !self
+                .areas
+                .iter()
+                .any(|x| x.start() < end && region.start() < x.end())
+    }
+
+    // highest gap below the stack that fits `num` pages
+    fn find_free_region(&self, num: usize) -> Option<Page> {
+        let bottom = VirtAddress::new(MMAP_BEGIN).get_page();
+        let mut top = VirtAddress::new(USER_REGION_END).get_page();
+        for area in self.areas.iter().rev() {
+            if area.end() <= top && top.offset_from(area.end()) as usize >= num {
+                break;
+            }
+            top = top.min(area.start());
+        }
+
+        if top >= bottom && top.offset_from(bottom) as usize >= num {
+            Some(top.offset(-(num as isize)))
+        } else {
+            None
+        }
+    }
+
+    // duplicates the user areas into `page_table`, every shared page becomes copy-on-write in both,
+    // so making it writable later still copies it first
+    pub fn fork(&mut self, page_table: ArchPageTable) -> Result<AddressSpace, AreaError> {
+        // the areas go first, so dropping a half-built child releases what it got
+        let mut child = AddressSpace::new(page_table);
+        child.areas = self.areas.clone();
+        child.program_break = self.program_break;
+        for area in self.areas.iter() {
+            let flags = (area.flags() - PageFlags::Writable) | PageFlags::CopyOnWrite;
+
+            let mut page = area.start();
+            while page != area.end() {
+                if let Some((frame, _)) = self.page_table.lookup(page) {
+                    self.page_table.protect(&PageRegion::new(page, 1), flags);
+                    child
+                        .page_table
+                        .map(
+                            &MappingRegion {
+                                phys_begin: frame,
+                                virt_begin: page,
+                                num: 1,
+                            },
+                            flags,
+                        )
+                        .map_err(|_| AreaError::OutOfMemory)?;
+                    FRAME_ALLOCATOR.lock().share(frame);
+                    child.resident += 1;
+                }
+                page = page.offset(1);
+            }
+        }
+        Ok(child)
+    }
+
+    pub fn handle_fault(&mut self, addr: VirtAddress, fault: FaultFlags) -> Result<(), FaultError> {
+        let page = addr.get_page();
+        let area = self.find_area(page).ok_or(FaultError::NoArea)?;
+        if !area.permits(fault) {
+            return Err(FaultError::AccessViolation);
+        } else if fault.contains(FaultFlags::Present) {
+            let flags = area.flags();
+            return if fault.contains(FaultFlags::Write) {
+                self.copy_on_write(page, flags)
+            } else {
+                Err(FaultError::AccessViolation)
+            };
+        }
+
+        match area.backing() {
+            Backing::Anonymous => {
+                let flags = area.flags();
+                let frame = FRAME_ALLOCATOR
+                    .lock()
+                    .alloc_for(1, FrameOwner::UserAnonymous)
+                    .map_err(|_| FaultError::OutOfMemory)?
+                    .start();
+                unsafe { borrow_from_phys_addr_mut::<[u8; FRAME_SIZE]>(frame.into()) }.fill(0);
+                let mapped = self.page_table.map(
+                    &MappingRegion {
+                        phys_begin: frame,
+                        virt_begin: page,
+                        num: 1,
+                    },
+                    flags,
+                );
+                if mapped.is_err() {
+                    FRAME_ALLOCATOR.lock().release(frame).unwrap();
+                    return Err(FaultError::OutOfMemory);
+                }
+                self.resident += 1;
+                Ok(())
+            }
+            Backing::Populated | Backing::Guard => Err(FaultError::AccessViolation),
+        }
+    }
+
+    fn copy_on_write(&mut self, page: Page, flags: PageFlags) -> Result<(), FaultError> {
+        let (frame, current) = self
+            .page_table
+            .lookup(page)
+            .ok_or(FaultError::AccessViolation)?;
+        if !current.contains(PageFlags::CopyOnWrite) {
+            return Err(FaultError::AccessViolation);
+        }
+
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        if !allocator.is_shared(frame) {
+            // the other owners are gone, take the frame over
+            drop(allocator);
+            self.page_table.protect(&PageRegion::new(page, 1), flags);
+            return Ok(());
+        }
+
+        let copy = allocator
+            .alloc_for(1, FrameOwner::UserAnonymous)
+            .map_err(|_| FaultError::OutOfMemory)?
+            .start();
+        drop(allocator);
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                borrow_from_phys_addr_mut::<[u8; FRAME_SIZE]>(frame.into()),
+                borrow_from_phys_addr_mut::<[u8; FRAME_SIZE]>(copy.into()),
+                1,
+            );
+        }
+        let mapped = self.page_table.map(
+            &MappingRegion {
+                phys_begin: copy,
+                virt_begin: page,
+                num: 1,
+            },
+            flags,
+        );
+        if mapped.is_err() {
+            FRAME_ALLOCATOR.lock().release(copy).unwrap();
+            return Err(FaultError::OutOfMemory);
+        }
+        release_mapped(frame, current);
+        Ok(())
+    }
+}
+
+// drops a mapping's share of `frame`, keeping what the hardware saw of it for the other owners
+fn release_mapped(frame: Frame, flags: PageFlags) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let descriptor = allocator.descriptor_mut(frame);
+    if flags.contains(PageFlags::Accessed) {
+        descriptor.set_state(FrameState::Accessed, true);
+    }
+    if flags.contains(PageFlags::Dirty) {
+        descriptor.set_state(FrameState::Dirty, true);
+    }
+    allocator.release(frame).unwrap();
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        for area in self.areas.iter() {
+            let mut page = area.start();
+            while page != area.end() {
+                if let Some((frame, flags)) = self.page_table.lookup(page) {
+                    release_mapped(frame, flags);
+                }
+                page = page.offset(1);
+            }
+        }
+    }
+}
@@ -1,217 +1,369 @@
 use core::alloc::{GlobalAlloc, Layout};
-use core::{cmp::Ordering, usize};
+use core::ptr::null_mut;
 
-use crate::sync::SpinLock;
+use crate::{arch::mm::page_table::PageTable as ArchPageTable, sync::SpinLock};
 
-use super::definitions::{KERNEL_HEAP_BEGIN, KERNEL_HEAP_SIZE};
+use super::{
+    definitions::{FRAME_SIZE, FrameAllocator, MappingRegion, PageFlags, PageTable, VirtAddress},
+    frame_allocator::FRAME_ALLOCATOR,
+    frame_descriptor::FrameOwner,
+    layout::{kernel_heap_begin, kernel_heap_end},
+    utils::share_kernel,
+};
 
-#[derive(Debug, Clone)]
-struct MemoryHole {
-    base: usize,
-    size: usize,
+#[cfg(feature = "kasan")]
+use super::kasan;
+
+// objects larger than the last class go to the page-granular path
+const SIZE_CLASSES: [usize; 7] = [16, 32, 64, 128, 256, 512, 1024];
+const INITIAL_HEAP_PAGES: usize = 16;
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// header at the beginning of every slab page
+#[repr(C)]
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+// a run of free pages inside the heap window
+struct FreeRun {
+    pages: usize,
+    next: *mut FreeRun,
 }
 
-struct DirectBlockAllocator {
-    base: *mut u8,
-    floor: usize,
-    ceiling: usize,
+struct SlabCache {
+    object_size: usize,
+    // slabs with at least one free object
+    partial: *mut Slab,
 }
 
-impl DirectBlockAllocator {
-    const fn new(base: *mut u8, size: usize) -> Self {
+struct HeapWindow {
+    // root only used to reach the kernel half's tables, which every address space shares
+    table: Option<ArchPageTable>,
+    top: usize,
+    free_runs: *mut FreeRun,
+}
+
+struct SlabAllocator {
+    caches: [SlabCache; SIZE_CLASSES.len()],
+    window: HeapWindow,
+}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
         Self {
-            base,
-            floor: 0,
-            ceiling: size,
+            object_size,
+            partial: null_mut(),
         }
     }
 
-    fn alloc(&mut self, layout: Layout) -> *mut u8 {
-        unsafe {
-            self.try_alloc_from_ceiling(layout)
-                .or_else(|| self.try_alloc_from_hole(layout))
-                .map(|x| self.base.offset(x as isize))
-                .unwrap_or(core::ptr::null_mut())
-        }
+    fn first_object_offset(&self) -> usize {
+        size_of::<Slab>().next_multiple_of(self.object_size)
     }
 
-    fn free(&mut self, ptr: *mut u8, layout: Layout) {
-        Self::insert_hole(
-            self,
-            &MemoryHole {
-                base: unsafe { ptr.offset_from(self.base) as usize },
-                size: layout.size(),
-            },
-        )
-        .unwrap();
-    }
-
-    fn try_alloc_from_ceiling(&mut self, layout: Layout) -> Option<usize> {
-        let size = layout.size();
-        let align = layout.align();
-
-        let mut result = None;
-        let rest = self.ceiling - self.floor;
-        if rest >= size {
-            let mut cand = self.ceiling - size;
-            if cand % align != 0 {
-                let offset = cand % align;
-
-                if rest >= size + offset + size_of::<MemoryHole>() {
-                    cand = cand - offset;
-                    let hole = MemoryHole {
-                        base: self.ceiling - offset,
-                        size: offset,
-                    };
-                    Self::insert_hole(self, &hole).unwrap();
-                    result = Some(cand);
-                    self.ceiling -= size + offset;
-                }
-            } else {
-                result = Some(cand);
-                self.ceiling -= size;
+    fn alloc(&mut self, window: &mut HeapWindow) -> *mut u8 {
+        if self.partial.is_null() {
+            match window.alloc_pages(1) {
+                Some(page) => self.init_slab(page as *mut Slab),
+                None => return null_mut(),
             }
         }
 
-        result
+        unsafe {
+            let slab = &mut *self.partial;
+            let object = slab.free;
+            slab.free = (*object).next;
+            slab.in_use += 1;
+            if slab.free.is_null() {
+                self.unlink(slab);
+            }
+            object as *mut u8
+        }
     }
 
-    fn try_alloc_from_hole(&mut self, layout: Layout) -> Option<usize> {
-        let hole_count = self.floor / size_of::<MemoryHole>();
-        let holes =
-            unsafe { core::slice::from_raw_parts_mut(self.base as *mut MemoryHole, hole_count) };
+    fn free(&mut self, ptr: *mut u8, window: &mut HeapWindow) {
+        unsafe {
+            let slab = &mut *((ptr as usize & !(FRAME_SIZE - 1)) as *mut Slab);
+            let was_full = slab.free.is_null();
+
+            let object = ptr as *mut FreeObject;
+            (*object).next = slab.free;
+            slab.free = object;
+            slab.in_use -= 1;
 
-        let size = layout.size();
-        let align = layout.align();
+            if was_full {
+                self.link(slab);
+            }
+
+            // keep the last partial slab around to avoid thrashing
+            if slab.in_use == 0 && !(slab.next.is_null() && slab.prev.is_null()) {
+                self.unlink(slab);
+                window.free_pages(slab as *mut Slab as usize, 1);
+            }
+        }
+    }
 
-        let mut result = None;
-        let mut to_delete = usize::MAX;
+    fn init_slab(&mut self, slab: *mut Slab) {
+        let base = slab as usize;
+        let mut free = null_mut();
+        let mut offset = FRAME_SIZE - self.object_size;
+        while offset >= self.first_object_offset() {
+            let object = (base + offset) as *mut FreeObject;
+            unsafe {
+                (*object).next = free;
+            }
+            free = object;
+            offset -= self.object_size;
+        }
 
-        for (i, hole) in holes.iter_mut().enumerate() {
-            if hole.size >= size {
-                let mut before = None;
-                let mut after = None;
-                let cand = hole.base;
-                if cand % align != 0 {
-                    let offset = align - cand % align;
-                    if hole.size >= size + offset {
-                        result = Some(cand + offset);
+        unsafe {
+            *slab = Slab {
+                next: null_mut(),
+                prev: null_mut(),
+                free,
+                in_use: 0,
+            };
+            self.link(&mut *slab);
+        }
+    }
 
-                        if size + offset == hole.size {
-                            before = Some(MemoryHole {
-                                base: hole.base,
-                                size: offset,
-                            });
-                        }
-                    }
-                } else {
-                    result = Some(cand)
-                }
+    fn link(&mut self, slab: &mut Slab) {
+        slab.prev = null_mut();
+        slab.next = self.partial;
+        if !self.partial.is_null() {
+            unsafe {
+                (*self.partial).prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
 
-                if result.is_some() {
-                    if result.unwrap() + size < hole.base + hole.size {
-                        after = Some(MemoryHole {
-                            base: result.unwrap() + size,
-                            size: hole.base + hole.size - result.unwrap() - size,
-                        })
-                    }
+    fn unlink(&mut self, slab: &mut Slab) {
+        unsafe {
+            if slab.prev.is_null() {
+                self.partial = slab.next;
+            } else {
+                (*slab.prev).next = slab.next;
+            }
+            if !slab.next.is_null() {
+                (*slab.next).prev = slab.prev;
+            }
+        }
+        slab.next = null_mut();
+        slab.prev = null_mut();
+    }
+}
 
-                    if before.is_some() && after.is_some() {
-                        *hole = before.unwrap();
-                        Self::insert_hole(self, &after.unwrap()).unwrap();
+impl HeapWindow {
+    const fn new() -> Self {
+        Self {
+            table: None,
+            top: 0,
+            free_runs: null_mut(),
+        }
+    }
+
+    fn alloc_pages(&mut self, count: usize) -> Option<usize> {
+        let mut prev: *mut FreeRun = null_mut();
+        let mut run = self.free_runs;
+        unsafe {
+            while !run.is_null() {
+                if (*run).pages == count {
+                    if prev.is_null() {
+                        self.free_runs = (*run).next;
                     } else {
-                        let new_hole = before.or(after);
-                        if let Some(nh) = new_hole {
-                            *hole = nh;
-                        } else {
-                            to_delete = i;
-                        }
+                        (*prev).next = (*run).next;
                     }
-                    break;
+                    return Some(run as usize);
+                } else if (*run).pages > count {
+                    // take the tail so the run header stays in place
+                    (*run).pages -= count;
+                    return Some(run as usize + (*run).pages * FRAME_SIZE);
                 }
+                prev = run;
+                run = (*run).next;
             }
         }
 
-        if to_delete != usize::MAX {
-            holes[to_delete..].rotate_left(1);
-            self.floor -= size_of::<MemoryHole>()
+        self.grow(count)
+    }
+
+    fn free_pages(&mut self, addr: usize, count: usize) {
+        let mut prev: *mut FreeRun = null_mut();
+        let mut next = self.free_runs;
+        unsafe {
+            while !next.is_null() && (next as usize) < addr {
+                prev = next;
+                next = (*next).next;
+            }
+
+            let run = addr as *mut FreeRun;
+            *run = FreeRun { pages: count, next };
+            if !next.is_null() && addr + count * FRAME_SIZE == next as usize {
+                (*run).pages += (*next).pages;
+                (*run).next = (*next).next;
+            }
+
+            if prev.is_null() {
+                self.free_runs = run;
+            } else if prev as usize + (*prev).pages * FRAME_SIZE == addr {
+                (*prev).pages += (*run).pages;
+                (*prev).next = (*run).next;
+            } else {
+                (*prev).next = run;
+            }
         }
-        result
     }
 
-    fn insert_hole(&mut self, hole: &MemoryHole) -> Result<(), ()> {
-        if self.ceiling - self.floor < size_of::<MemoryHole>() {
-            Err(())
-        } else {
-            let hole_count = self.floor / size_of::<MemoryHole>();
-            let holes = unsafe {
-                core::slice::from_raw_parts_mut(self.base as *mut MemoryHole, hole_count + 1)
+    // maps fresh frames at the top of the window
+    fn grow(&mut self, count: usize) -> Option<usize> {
+        let begin = self.top;
+        if begin + count * FRAME_SIZE > kernel_heap_end() {
+            return None;
+        }
+        // before taking any frame, which would leak otherwise
+        let table = self.table.as_mut()?;
+
+        for _ in 0..count {
+            let Ok(frames) = FRAME_ALLOCATOR.lock().alloc_for(1, FrameOwner::KernelHeap) else {
+                break;
             };
-            holes[hole_count] = hole.clone();
-            holes.sort_unstable_by(|l, r| l.base.cmp(&r.base).reverse());
-            self.floor += size_of::<MemoryHole>();
-            Self::merge_holes(self);
-            Ok(())
-        }
-    }
-
-    fn merge_holes(&mut self) {
-        let hole_count = self.floor / size_of::<MemoryHole>();
-        let holes =
-            unsafe { core::slice::from_raw_parts_mut(self.base as *mut MemoryHole, hole_count) };
-
-        let mut num = 0;
-        for i in 0..hole_count {
-            if holes[i].base == self.ceiling {
-                self.ceiling -= holes[i].size;
-                holes[i].size = 0;
-                num += 1;
-            } else if i < hole_count - 1 && holes[i].base + holes[i].size == holes[i + 1].base {
-                holes[i + 1].base = holes[i].base;
-                holes[i + 1].size += holes[i].size;
-                holes[i].size = 0;
-                num += 1;
+            #[cfg(feature = "kasan")]
+            if kasan::populate_shadow(table, self.top).is_err() {
+                FRAME_ALLOCATOR.lock().free(&frames).unwrap();
+                break;
             }
+            let mapped = table.map(
+                &MappingRegion {
+                    phys_begin: frames.start(),
+                    virt_begin: VirtAddress::new(self.top).get_page(),
+                    num: 1,
+                },
+                PageFlags::Writable | PageFlags::Global,
+            );
+            if mapped.is_err() {
+                FRAME_ALLOCATOR.lock().free(&frames).unwrap();
+                break;
+            }
+            self.top += FRAME_SIZE;
         }
 
-        holes.sort_unstable_by(|l, r| {
-            if l.size == 0 && r.size != 0 {
-                Ordering::Greater
-            } else if l.size != 0 && r.size == 0 {
-                Ordering::Less
-            } else {
-                l.base.cmp(&r.base).reverse()
+        let mapped = (self.top - begin) / FRAME_SIZE;
+        if mapped == count {
+            Some(begin)
+        } else {
+            // out of frames, keep what we have already mapped
+            if mapped != 0 {
+                self.free_pages(begin, mapped);
             }
-        });
+            None
+        }
+    }
+}
 
-        self.floor -= num * size_of::<MemoryHole>();
+impl SlabAllocator {
+    const fn new() -> Self {
+        Self {
+            caches: [
+                SlabCache::new(SIZE_CLASSES[0]),
+                SlabCache::new(SIZE_CLASSES[1]),
+                SlabCache::new(SIZE_CLASSES[2]),
+                SlabCache::new(SIZE_CLASSES[3]),
+                SlabCache::new(SIZE_CLASSES[4]),
+                SlabCache::new(SIZE_CLASSES[5]),
+                SlabCache::new(SIZE_CLASSES[6]),
+            ],
+            window: HeapWindow::new(),
+        }
+    }
+
+    fn size_class(layout: Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        SIZE_CLASSES.iter().position(|x| *x >= size)
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Some(class) = Self::size_class(layout) {
+            self.caches[class].alloc(&mut self.window)
+        } else if layout.align() > FRAME_SIZE {
+            null_mut()
+        } else {
+            self.window
+                .alloc_pages(layout.size().div_ceil(FRAME_SIZE))
+                .map_or(null_mut(), |x| x as *mut u8)
+        }
+    }
+
+    fn free(&mut self, ptr: *mut u8, layout: Layout) {
+        if let Some(class) = Self::size_class(layout) {
+            self.caches[class].free(ptr, &mut self.window);
+        } else {
+            self.window
+                .free_pages(ptr as usize, layout.size().div_ceil(FRAME_SIZE));
+        }
     }
 }
 
 struct GlobalAllocator {
-    allocator: SpinLock<DirectBlockAllocator>,
+    allocator: SpinLock<SlabAllocator>,
 }
 
 unsafe impl GlobalAlloc for GlobalAllocator {
+    #[cfg(not(feature = "kasan"))]
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         self.allocator.lock().alloc(layout)
     }
 
+    #[cfg(not(feature = "kasan"))]
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         self.allocator.lock().free(ptr, layout);
     }
+
+    // every object is wrapped in redzones and frees go through the quarantine
+    #[cfg(feature = "kasan")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let chunk = self.allocator.lock().alloc(kasan::chunk_layout(layout));
+        if chunk.is_null() {
+            chunk
+        } else {
+            unsafe { kasan::on_alloc(chunk, layout) }
+        }
+    }
+
+    #[cfg(feature = "kasan")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some((chunk, layout)) = unsafe { kasan::on_free(ptr, layout) } {
+            self.allocator.lock().free(chunk, layout);
+        }
+    }
 }
 
 impl GlobalAllocator {
     const fn new() -> Self {
         Self {
-            allocator: SpinLock::new(DirectBlockAllocator::new(
-                KERNEL_HEAP_BEGIN as *mut u8,
-                KERNEL_HEAP_SIZE,
-            )),
+            allocator: SpinLock::new(SlabAllocator::new()),
         }
     }
 }
 
 #[global_allocator]
 static GLOBAL_ALLOCATOR: GlobalAllocator = GlobalAllocator::new();
+
+pub fn init_heap() {
+    let mut allocator = GLOBAL_ALLOCATOR.allocator.lock();
+    let window = &mut allocator.window;
+    let mut table = ArchPageTable::new().expect("Cannot allocate kernel heap table.");
+    share_kernel(&mut table);
+    window.table = Some(table);
+    window.top = kernel_heap_begin();
+    let pages = window
+        .grow(INITIAL_HEAP_PAGES)
+        .expect("Cannot map initial kernel heap.");
+    window.free_pages(pages, INITIAL_HEAP_PAGES);
+}
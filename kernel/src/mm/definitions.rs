@@ -1,18 +1,6 @@
 use bitflags::bitflags;
 
 pub const FRAME_SIZE: usize = 4096;
-pub const KERNEL_REGION_BEGIN: usize = 0xffff_8000_0000_0000;
-pub const PHYSICAL_MAP_BEGIN: usize = 0xffff_8100_0000_0000;
-pub const PHYSICAL_MAP_SIZE: usize = 0x0000_0100_0000_0000;
-pub const PHYSICAL_MAP_END: usize = PHYSICAL_MAP_BEGIN + PHYSICAL_MAP_SIZE;
-pub const KERNEL_HEAP_BEGIN: usize = 0xffff_8200_0000_0000;
-pub const KERNEL_HEAP_SIZE: usize = 16 * 1024 * 1024;
-pub const KERNEL_HEAP_END: usize = KERNEL_HEAP_BEGIN + KERNEL_HEAP_SIZE;
-pub const KERNEL_STACK_BEGIN: usize = 0xffff_8800_0000_0000;
-pub const KERNEL_STACK_SIZE: usize = 0x0000_0100_0000_0000;
-pub const KERNEL_ISTACK_END: usize = 0xffff_8900_0000_0000;
-pub const APP_STACK_BEGIN: usize = APP_STACK_END - FRAME_SIZE;
-pub const APP_STACK_END: usize = 0x8000_0000_0000;
 
 unsafe extern "C" {
     pub static TEXT_START: u64;
@@ -159,7 +147,7 @@ impl PageRegion {
 
     #[inline]
     pub fn end(&self) -> Page {
-        self.begin
+        self.begin.offset(self.num as isize)
     }
 }
 
@@ -186,25 +174,41 @@ impl FrameRegion {
 
     #[inline]
     pub fn end(&self) -> Frame {
-        self.begin
+        self.begin.offset(self.num as isize)
     }
 }
 
 bitflags! {
-    #[derive(Clone, Copy)]
-    pub struct PageFlags: u8 {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PageFlags: u16 {
         const Writable = 1;
         const Usermode = 2;
         const Executable = 4;
+        // read-only until the first write copies the frame
+        const CopyOnWrite = 8;
+        // map with 2M or 1G pages wherever both addresses are aligned
+        const Huge = 16;
+        // stays cached across address space switches, kernel half only
+        const Global = 32;
+        // memory type, at most one of these, plain pages are write-back
+        const Uncached = 64;
+        const WriteThrough = 128;
+        const WriteCombining = 256;
+        // set by the hardware, only reported by `lookup`
+        const Accessed = 512;
+        const Dirty = 1024;
     }
 }
 
 pub trait PageTable {
-    fn map(&mut self, region: &MappingRegion, flags: PageFlags);
-    fn unmap(&mut self, region: &PageRegion);
+    fn map(&mut self, region: &MappingRegion, flags: PageFlags) -> Result<(), FrameAllocError>;
+    // only fails when a huge page partly inside `region` cannot be split
+    fn unmap(&mut self, region: &PageRegion) -> Result<(), FrameAllocError>;
     unsafe fn bind(&self);
     unsafe fn bind_and_switch_stack(&self, sp: usize);
     fn resolve(&self, page: Page) -> Option<Frame>;
+    fn lookup(&self, page: Page) -> Option<(Frame, PageFlags)>;
+    fn protect(&mut self, region: &PageRegion, flags: PageFlags);
 }
 
 #[derive(Debug)]
@@ -215,6 +219,12 @@ pub enum FrameAllocError {
 
 #[derive(Debug)]
 pub enum FrameFreeError {
+    OutOfRange,
+    DoubleFree,
+    // not from the usable regions
+    Reserved,
+    // has other owners, see `release`
+    Shared,
     Unknown,
 }
 
@@ -1,82 +1,277 @@
-use core::cmp::Ordering;
-
 use lazy_static::lazy_static;
 
-use super::definitions::{Frame, FrameAllocError, FrameFreeError, FrameRegion};
+use super::{
+    definitions::{
+        FRAME_SIZE, Frame, FrameAllocError, FrameAllocator, FrameFreeError, FrameRegion,
+    },
+    frame_descriptor::{FrameDescriptor, FrameOwner, FrameState, NIL, NOT_FREE},
+    utils::calculate_pptr_from_phys_addr,
+};
+
+use crate::{sync::SpinLockNoIrq, trace};
 
-use crate::sync::SpinLock;
+// largest block is 2^MAX_ORDER frames (16M)
+pub const MAX_ORDER: usize = 12;
 
-pub struct StaticFrameAllocator {
-    blocks: [FrameRegion; 64],
+pub struct BuddyFrameAllocator {
+    heads: [u32; MAX_ORDER + 1],
+    // one descriptor per frame, free lists are linked through them
+    // kept physical, the direct map moves once during boot
+    descriptors: Frame,
+    frame_count: usize,
+    total_frames: usize,
+    free_frames: usize,
 }
 
-impl super::definitions::FrameAllocator for StaticFrameAllocator {
+impl FrameAllocator for BuddyFrameAllocator {
     fn alloc(&mut self, count: usize) -> Result<FrameRegion, FrameAllocError> {
-        let mut result = None;
-        for block in self.blocks.iter_mut() {
-            if block.size() >= count {
-                let new_block = FrameRegion::new(block.start(), block.size() - count);
-                result = Some(FrameRegion::new(
-                    block.start().offset(new_block.size() as isize),
-                    count,
-                ));
-                *block = new_block
-            } else if block.size() == 0 {
-                break;
+        self.alloc_for(count, FrameOwner::Kernel)
+    }
+
+    fn free(&mut self, region: &FrameRegion) -> Result<(), FrameFreeError> {
+        let (begin, end) = (region.start().get_index(), region.end().get_index());
+        if begin == 0 || end > self.frame_count || begin > end {
+            return Err(FrameFreeError::OutOfRange);
+        }
+
+        // frames still shared have to go through `release`
+        for descriptor in (begin..end).map(|x| self.descriptor_at(x)) {
+            match descriptor.owner {
+                FrameOwner::Free => return Err(FrameFreeError::DoubleFree),
+                FrameOwner::Reserved => return Err(FrameFreeError::Reserved),
+                _ if descriptor.refcount != 1 => return Err(FrameFreeError::Shared),
+                _ => {}
             }
         }
 
-        self.sort_blocks();
+        self.free_range(begin, end);
+        Ok(())
+    }
+}
 
-        result.ok_or_else(|| FrameAllocError::OutOfMemory)
+impl BuddyFrameAllocator {
+    pub fn new() -> Self {
+        Self {
+            heads: [NIL; MAX_ORDER + 1],
+            descriptors: Frame::zero(),
+            frame_count: 0,
+            total_frames: 0,
+            free_frames: 0,
+        }
     }
 
-    fn free(&mut self, region: &FrameRegion) -> Result<(), FrameFreeError> {
-        if self.blocks[63].size() != 0 {
-            Err(FrameFreeError::Unknown)
-        } else {
-            self.blocks[63] = region.clone();
-            self.sort_blocks();
-
-            for i in 1..self.blocks.len() {
-                if self.blocks[i].size() == 0 {
-                    break;
-                } else if self.blocks[i - 1].end() == self.blocks[i].start() {
-                    self.blocks[i - 1] = FrameRegion::new(
-                        self.blocks[i - 1].start(),
-                        self.blocks[i - 1].size() + self.blocks[i].size(),
-                    );
-                    self.blocks[i] = FrameRegion::new(Frame::zero(), 0);
-                }
+    // seeds the allocator from all usable regions
+    // the descriptor table is carved out of the first region large enough to hold it
+    pub fn init<I: Iterator<Item = FrameRegion> + Clone>(&mut self, regions: I) {
+        self.frame_count = regions
+            .clone()
+            .map(|x| x.end().get_index())
+            .max()
+            .unwrap_or(0);
+
+        let table_frames = (self.frame_count * size_of::<FrameDescriptor>()).div_ceil(FRAME_SIZE);
+        let table = regions
+            .clone()
+            .find(|x| x.start().get_index() != 0 && x.size() >= table_frames)
+            .expect("No region can hold the frame table.")
+            .start();
+
+        // everything outside the usable regions stays reserved
+        self.descriptors = table;
+        let reserved = FrameDescriptor {
+            refcount: 1,
+            owner: FrameOwner::Reserved,
+            state: FrameState::Pinned,
+            ..FrameDescriptor::EMPTY
+        };
+        for i in 0..self.frame_count {
+            *self.descriptor_at(i) = reserved;
+        }
+
+        for region in regions {
+            // frame 0 stands for "no frame" in page tables
+            let mut begin = region.start().get_index().max(1);
+            let end = region.end().get_index();
+            if begin == table.get_index() {
+                begin += table_frames;
             }
+            if begin < end {
+                self.total_frames += end - begin;
+                self.free_range(begin, end);
+            }
+        }
+
+        trace!(
+            "Frame allocator: {} frames free of {}.",
+            self.free_frames, self.total_frames
+        );
+    }
+
+    pub fn alloc_for(
+        &mut self,
+        count: usize,
+        owner: FrameOwner,
+    ) -> Result<FrameRegion, FrameAllocError> {
+        if count == 0 {
+            return Err(FrameAllocError::Unknown);
+        }
+
+        let order = count.next_power_of_two().trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            return Err(FrameAllocError::OutOfMemory);
+        }
+
+        let mut k = order;
+        while k <= MAX_ORDER && self.heads[k] == NIL {
+            k += 1;
+        }
+        if k > MAX_ORDER {
+            return Err(FrameAllocError::OutOfMemory);
+        }
+
+        let block = self.heads[k] as usize;
+        self.remove(block, k);
+        while k > order {
+            k -= 1;
+            self.push(block + (1 << k), k);
+        }
+        self.free_frames -= 1 << order;
+
+        // give back the tail of a non power-of-two request
+        if count < 1 << order {
+            self.free_range(block + count, block + (1 << order));
+        }
+
+        // the kernel's own frames never move
+        let state = match owner {
+            FrameOwner::Kernel | FrameOwner::KernelHeap | FrameOwner::PageTable => {
+                FrameState::Pinned
+            }
+            _ => FrameState::empty(),
+        };
+        for i in block..block + count {
+            *self.descriptor_at(i) = FrameDescriptor {
+                refcount: 1,
+                owner,
+                state,
+                ..FrameDescriptor::EMPTY
+            };
+        }
 
-            self.sort_blocks();
+        Ok(FrameRegion::new(Frame::new(block), count))
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    pub fn descriptor(&self, frame: Frame) -> &FrameDescriptor {
+        assert!(frame.get_index() < self.frame_count);
+        self.descriptor_at(frame.get_index())
+    }
+
+    pub fn descriptor_mut(&mut self, frame: Frame) -> &mut FrameDescriptor {
+        assert!(frame.get_index() < self.frame_count);
+        self.descriptor_at(frame.get_index())
+    }
+
+    // adds an owner to an allocated frame
+    pub fn share(&mut self, frame: Frame) {
+        self.descriptor_mut(frame).refcount += 1;
+    }
+
+    pub fn is_shared(&self, frame: Frame) -> bool {
+        self.descriptor(frame).refcount > 1
+    }
+
+    // drops an owner, the frame is freed together with its last one
+    pub fn release(&mut self, frame: Frame) -> Result<(), FrameFreeError> {
+        if frame.get_index() >= self.frame_count {
+            return Err(FrameFreeError::OutOfRange);
+        }
+
+        let descriptor = self.descriptor_at(frame.get_index());
+        if descriptor.refcount > 1 {
+            descriptor.refcount -= 1;
             Ok(())
+        } else {
+            self.free(&FrameRegion::new(frame, 1))
         }
     }
-}
 
-impl StaticFrameAllocator {
-    pub fn new() -> Self {
-        Self {
-            blocks: core::array::from_fn(|_| FrameRegion::new(Frame::zero(), 0)),
+    // largest aligned block starting at `begin` that fits before `end`
+    fn block_order(begin: usize, end: usize) -> usize {
+        let align = begin.trailing_zeros() as usize;
+        let fit = (usize::BITS - 1 - (end - begin).leading_zeros()) as usize;
+        align.min(fit).min(MAX_ORDER)
+    }
+
+    fn free_range(&mut self, mut begin: usize, end: usize) {
+        for i in begin..end {
+            *self.descriptor_at(i) = FrameDescriptor::EMPTY;
+        }
+
+        while begin < end {
+            let order = Self::block_order(begin, end);
+            self.free_block(begin, order);
+            begin += 1 << order;
         }
     }
 
-    fn sort_blocks(&mut self) -> () {
-        self.blocks.sort_unstable_by(|l, r| {
-            if l.size() == 0 && r.size() != 0 {
-                Ordering::Greater
-            } else if l.size() != 0 && r.size() == 0 {
-                Ordering::Less
-            } else {
-                l.start().cmp(&r.start())
+    fn free_block(&mut self, mut block: usize, mut order: usize) {
+        self.free_frames += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = block ^ (1 << order);
+            if buddy >= self.frame_count || self.descriptor_at(buddy).order != order as u8 {
+                break;
             }
-        });
+            self.remove(buddy, order);
+            block = block.min(buddy);
+            order += 1;
+        }
+        self.push(block, order);
+    }
+
+    fn descriptor_at(&self, frame: usize) -> &'static mut FrameDescriptor {
+        unsafe {
+            &mut *calculate_pptr_from_phys_addr::<FrameDescriptor>(self.descriptors.into())
+                .add(frame)
+        }
+    }
+
+    fn push(&mut self, frame: usize, order: usize) {
+        let head = self.heads[order];
+        let descriptor = self.descriptor_at(frame);
+        descriptor.prev = NIL;
+        descriptor.next = head;
+        descriptor.order = order as u8;
+        if head != NIL {
+            self.descriptor_at(head as usize).prev = frame as u32;
+        }
+        self.heads[order] = frame as u32;
+    }
+
+    fn remove(&mut self, frame: usize, order: usize) {
+        let descriptor = self.descriptor_at(frame);
+        let (prev, next) = (descriptor.prev, descriptor.next);
+        descriptor.order = NOT_FREE;
+        if prev != NIL {
+            self.descriptor_at(prev as usize).next = next;
+        } else {
+            self.heads[order] = next;
+        }
+        if next != NIL {
+            self.descriptor_at(next as usize).prev = prev;
+        }
     }
 }
 
 lazy_static! {
-    pub static ref FRAME_ALLOCATOR: SpinLock<StaticFrameAllocator> =
-        SpinLock::new(StaticFrameAllocator::new());
+    // the page fault handler takes it with interrupts off, so nobody may be preempted holding it
+    pub static ref FRAME_ALLOCATOR: SpinLockNoIrq<BuddyFrameAllocator> =
+        SpinLockNoIrq::new(BuddyFrameAllocator::new());
 }
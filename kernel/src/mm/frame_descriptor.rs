@@ -0,0 +1,73 @@
+use bitflags::bitflags;
+
+// who an allocated frame belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameOwner {
+    Free,
+    // never handed out: outside the usable regions, or the descriptor table itself
+    Reserved,
+    Kernel,
+    KernelHeap,
+    PageTable,
+    UserAnonymous,
+    PageCache,
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct FrameState: u8 {
+        // must stay where it is, e.g. while a device uses it
+        const Pinned = 1;
+        const Dirty = 2;
+        const Accessed = 4;
+    }
+}
+
+pub(super) const NIL: u32 = u32::MAX;
+pub(super) const NOT_FREE: u8 = u8::MAX;
+
+// one per physical frame, indexed by `Frame::get_index()`
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FrameDescriptor {
+    // free-list links, only used by the first frame of a free block
+    pub(super) prev: u32,
+    pub(super) next: u32,
+    pub(super) refcount: u32,
+    // order of the free block starting here, or NOT_FREE
+    pub(super) order: u8,
+    pub(super) owner: FrameOwner,
+    pub(super) state: FrameState,
+}
+
+impl FrameDescriptor {
+    pub(super) const EMPTY: Self = Self {
+        prev: NIL,
+        next: NIL,
+        refcount: 0,
+        order: NOT_FREE,
+        owner: FrameOwner::Free,
+        state: FrameState::empty(),
+    };
+
+    pub fn refcount(&self) -> u32 {
+        self.refcount
+    }
+
+    pub fn owner(&self) -> FrameOwner {
+        self.owner
+    }
+
+    pub fn state(&self) -> FrameState {
+        self.state
+    }
+
+    pub fn is_free(&self) -> bool {
+        self.owner == FrameOwner::Free
+    }
+
+    pub fn set_state(&mut self, state: FrameState, val: bool) {
+        self.state.set(state, val);
+    }
+}
@@ -0,0 +1,234 @@
+use core::alloc::Layout;
+
+use crate::{arch::mm::page_table::PageTable as ArchPageTable, sync::SpinLock, task, trace};
+
+use super::{
+    definitions::{
+        FRAME_SIZE, FrameAllocError, FrameAllocator, MappingRegion, PageFlags, PageTable,
+        VirtAddress,
+    },
+    frame_allocator::FRAME_ALLOCATOR,
+    frame_descriptor::FrameOwner,
+    layout::{KASAN_SHADOW_BEGIN, kernel_heap_begin},
+    utils::borrow_from_phys_addr_mut,
+};
+
+// one shadow byte per granule: 0 accessible, 1-7 that many leading bytes accessible
+const GRANULE: usize = 8;
+const SHADOW_UNALLOCATED: u8 = 0xfc;
+const SHADOW_LEFT_REDZONE: u8 = 0xfa;
+const SHADOW_RIGHT_REDZONE: u8 = 0xfb;
+const SHADOW_FREED: u8 = 0xfd;
+
+// bytes written into redzones and freed memory, checked on free and on leaving quarantine
+const REDZONE_PATTERN: u8 = 0xcc;
+const FREED_PATTERN: u8 = 0xdd;
+
+const REDZONE: usize = 32;
+const MAGIC_LIVE: u64 = 0x6b61_7361_6e5f_6f6b;
+const MAGIC_FREED: u64 = 0x6b61_7361_6e5f_6672;
+const QUARANTINE_SIZE: usize = 256;
+
+// sits right before the pointer handed out, inside the left redzone
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    // task that was running when it was allocated
+    owner: usize,
+    // offset of the pointer from the chunk the allocator returned
+    offset: usize,
+}
+
+// freed pointers with their layouts, only given back to the allocator once pushed out
+struct Quarantine {
+    chunks: [Option<(usize, Layout)>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+static QUARANTINE: SpinLock<Quarantine> = SpinLock::new(Quarantine {
+    chunks: [None; QUARANTINE_SIZE],
+    next: 0,
+});
+
+fn shadow_of(addr: usize) -> *mut u8 {
+    (KASAN_SHADOW_BEGIN + (addr - kernel_heap_begin()) / GRANULE) as *mut u8
+}
+
+fn poison(addr: usize, len: usize, value: u8) {
+    unsafe {
+        core::ptr::write_bytes(shadow_of(addr), value, len.div_ceil(GRANULE));
+    }
+}
+
+fn shadow(addr: usize) -> u8 {
+    unsafe { *shadow_of(addr) }
+}
+
+fn left_redzone(layout: Layout) -> usize {
+    REDZONE.next_multiple_of(layout.align())
+}
+
+// what the allocator is asked for in place of `layout`
+pub fn chunk_layout(layout: Layout) -> Layout {
+    let size = left_redzone(layout) + layout.size().next_multiple_of(GRANULE) + REDZONE;
+    Layout::from_size_align(size, layout.align().max(GRANULE)).unwrap()
+}
+
+// maps the shadow page covering the heap page at `addr`, new shadow is unallocated
+pub fn populate_shadow(table: &mut ArchPageTable, addr: usize) -> Result<(), FrameAllocError> {
+    let page = VirtAddress::new(shadow_of(addr) as usize).get_page();
+    if table.resolve(page).is_some() {
+        return Ok(());
+    }
+
+    let frames = FRAME_ALLOCATOR.lock().alloc_for(1, FrameOwner::Kernel)?;
+    unsafe { borrow_from_phys_addr_mut::<[u8; FRAME_SIZE]>(frames.start().into()) }
+        .fill(SHADOW_UNALLOCATED);
+    let mapped = table.map(
+        &MappingRegion {
+            phys_begin: frames.start(),
+            virt_begin: page,
+            num: 1,
+        },
+        PageFlags::Writable | PageFlags::Global,
+    );
+    if mapped.is_err() {
+        FRAME_ALLOCATOR.lock().free(&frames).unwrap();
+    }
+    mapped
+}
+
+fn report(what: &str, addr: usize, size: usize, owner: usize) -> ! {
+    trace!(
+        "KASAN: {} at {:#x}, {} byte allocation owned by task {}, current task {}.",
+        what,
+        addr,
+        size,
+        owner,
+        task::running_task_id()
+    );
+    panic!("KASAN: {} at {:#x}.", what, addr);
+}
+
+// `chunk` was just returned by the allocator for `chunk_layout(layout)`
+pub unsafe fn on_alloc(chunk: *mut u8, layout: Layout) -> *mut u8 {
+    let base = chunk as usize;
+    let total = chunk_layout(layout).size();
+    // handing out memory that is still live means the free lists are corrupt
+    for granule in (base..base + total).step_by(GRANULE) {
+        let value = shadow(granule);
+        if value < GRANULE as u8 || value == SHADOW_LEFT_REDZONE || value == SHADOW_RIGHT_REDZONE {
+            report(
+                "allocation overlapping live memory",
+                granule,
+                layout.size(),
+                0,
+            );
+        }
+    }
+
+    let offset = left_redzone(layout);
+    let ptr = base + offset;
+    let size = layout.size();
+    unsafe {
+        core::ptr::write_bytes(chunk, REDZONE_PATTERN, offset);
+        core::ptr::write_bytes(
+            (ptr + size) as *mut u8,
+            REDZONE_PATTERN,
+            base + total - ptr - size,
+        );
+        *((ptr - size_of::<Header>()) as *mut Header) = Header {
+            magic: MAGIC_LIVE,
+            size,
+            owner: task::running_task_id(),
+            offset,
+        };
+    }
+
+    poison(base, offset, SHADOW_LEFT_REDZONE);
+    poison(ptr, size, 0);
+    if size % GRANULE != 0 {
+        unsafe {
+            *shadow_of(ptr + size / GRANULE * GRANULE) = (size % GRANULE) as u8;
+        }
+    }
+    let right = ptr + size.next_multiple_of(GRANULE);
+    poison(right, base + total - right, SHADOW_RIGHT_REDZONE);
+    ptr as *mut u8
+}
+
+// checks and quarantines `ptr`, returning a chunk that left the quarantine and can be freed
+pub unsafe fn on_free(ptr: *mut u8, layout: Layout) -> Option<(*mut u8, Layout)> {
+    let ptr = ptr as usize;
+    let header = unsafe { &mut *((ptr - size_of::<Header>()) as *mut Header) };
+    match header.magic {
+        MAGIC_LIVE => {}
+        MAGIC_FREED => report("double free", ptr, header.size, header.owner),
+        _ => report("invalid free", ptr, layout.size(), 0),
+    }
+    if shadow(ptr) == SHADOW_FREED {
+        report("double free", ptr, header.size, header.owner);
+    }
+    if header.size != layout.size() || header.offset != left_redzone(layout) {
+        report(
+            "free with a mismatched layout",
+            ptr,
+            header.size,
+            header.owner,
+        );
+    }
+
+    let base = ptr - header.offset;
+    let total = chunk_layout(layout).size();
+    let intact = |begin: usize, end: usize| {
+        (begin..end).all(|x| unsafe { *(x as *const u8) } == REDZONE_PATTERN)
+    };
+    if !intact(base, ptr - size_of::<Header>()) {
+        report("heap underflow", ptr, header.size, header.owner);
+    }
+    if !intact(ptr + header.size, base + total) {
+        report("heap overflow", ptr, header.size, header.owner);
+    }
+
+    header.magic = MAGIC_FREED;
+    unsafe {
+        core::ptr::write_bytes(ptr as *mut u8, FREED_PATTERN, header.size);
+    }
+    poison(base, total, SHADOW_FREED);
+
+    let mut quarantine = QUARANTINE.lock();
+    let slot = quarantine.next;
+    quarantine.next = (slot + 1) % QUARANTINE_SIZE;
+    let (evicted, layout) = quarantine.chunks[slot].replace((ptr, layout))?;
+    drop(quarantine);
+
+    let header = unsafe { &*((evicted - size_of::<Header>()) as *const Header) };
+    let written =
+        (evicted..evicted + layout.size()).any(|x| unsafe { *(x as *const u8) } != FREED_PATTERN);
+    if written {
+        report("use after free", evicted, header.size, header.owner);
+    }
+    Some(((evicted - header.offset) as *mut u8, chunk_layout(layout)))
+}
+
+// for explicit checks where an access has to stay inside live heap memory
+pub fn check_access(addr: usize, len: usize) {
+    let end = addr + len;
+    let mut granule = addr / GRANULE * GRANULE;
+    while granule < end {
+        let value = shadow(granule);
+        // partially accessible granules allow only their first `value` bytes
+        let used = end.min(granule + GRANULE) - granule;
+        if value != 0 && !(value < GRANULE as u8 && used <= value as usize) {
+            let what = match value {
+                SHADOW_FREED => "use after free",
+                SHADOW_LEFT_REDZONE => "heap underflow",
+                SHADOW_RIGHT_REDZONE => "heap overflow",
+                _ => "wild access",
+            };
+            report(what, granule.max(addr), len, 0);
+        }
+        granule += GRANULE;
+    }
+}
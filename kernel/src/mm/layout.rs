@@ -0,0 +1,133 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{arch::x86_64::utils::random_u64, trace};
+
+use super::definitions::FRAME_SIZE;
+
+// one pdpt entry, 1G, so the direct map can still use 1G pages
+const SLIDE_ALIGN: usize = 0x0000_0000_4000_0000;
+
+// user half
+pub const MMAP_BEGIN: usize = 0x0000_0000_0001_0000;
+pub const USER_REGION_END: usize = 0x0000_8000_0000_0000;
+pub const APP_STACK_SIZE: usize = 8 * 1024 * 1024;
+pub const APP_STACK_END: usize = USER_REGION_END;
+// the stack top moves down by up to 1G
+const APP_STACK_RANDOM_PAGES: usize = 1 << 18;
+// gap of up to 32M between the program and its heap
+const HEAP_RANDOM_PAGES: usize = 1 << 13;
+// position independent programs are loaded somewhere in [PIE_BASE, PIE_BASE + 1T)
+const PIE_BASE: usize = 0x0000_5555_0000_0000;
+const PIE_RANDOM_PAGES: usize = 1 << 28;
+
+// kernel half, the image itself is placed by the linker
+pub const KERNEL_REGION_BEGIN: usize = 0xffff_8000_0000_0000;
+// where the bootloader maps physical memory, only used until our own direct map is up
+pub const BOOT_PHYSICAL_MAP_BEGIN: usize = 0xffff_8100_0000_0000;
+pub const KERNEL_ISTACK_END: usize = 0xffff_8900_0000_0000;
+pub const PHYSICAL_MAP_SIZE: usize = 0x0000_0100_0000_0000;
+pub const KERNEL_HEAP_SIZE: usize = 0x0000_0080_0000_0000;
+pub const KERNEL_STACK_REGION_SIZE: usize = 0x0000_0100_0000_0000;
+// per task, each stack also gets an unmapped guard page below it
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+// vmalloc and ioremap
+pub const VMALLOC_SIZE: usize = 0x0000_0100_0000_0000;
+// shadow of the kernel heap for the sanitizer, a byte per 8 heap bytes wherever the heap slid to
+pub const KASAN_SHADOW_BEGIN: usize = 0xffff_a000_0000_0000;
+pub const KASAN_SHADOW_SIZE: usize = KERNEL_HEAP_SIZE / 8;
+
+// each slid region starts at the bottom of its window, moved up by whole pdpt entries
+const PHYSICAL_MAP_WINDOW: usize = 0xffff_9000_0000_0000;
+const KERNEL_HEAP_WINDOW: usize = 0xffff_b000_0000_0000;
+const KERNEL_STACK_WINDOW: usize = 0xffff_c000_0000_0000;
+const VMALLOC_WINDOW: usize = 0xffff_d000_0000_0000;
+const WINDOW_SIZE: usize = 0x0000_1000_0000_0000;
+
+static PHYSICAL_MAP_BEGIN: AtomicUsize = AtomicUsize::new(PHYSICAL_MAP_WINDOW);
+static KERNEL_HEAP_BEGIN: AtomicUsize = AtomicUsize::new(KERNEL_HEAP_WINDOW);
+static KERNEL_STACK_REGION_BEGIN: AtomicUsize = AtomicUsize::new(KERNEL_STACK_WINDOW);
+static VMALLOC_BEGIN: AtomicUsize = AtomicUsize::new(VMALLOC_WINDOW);
+// the direct map used right now, the bootloader's until `switch_physical_map`
+static ACTIVE_PHYSICAL_MAP: AtomicUsize = AtomicUsize::new(BOOT_PHYSICAL_MAP_BEGIN);
+
+// picks the kernel slides if the kaslr feature is on, must run before anything is mapped into the
+// slid regions
+pub fn init_layout() {
+    if cfg!(feature = "kaslr") {
+        PHYSICAL_MAP_BEGIN.store(
+            PHYSICAL_MAP_WINDOW + random_slide(PHYSICAL_MAP_SIZE),
+            Ordering::Relaxed,
+        );
+        KERNEL_HEAP_BEGIN.store(
+            KERNEL_HEAP_WINDOW + random_slide(KERNEL_HEAP_SIZE),
+            Ordering::Relaxed,
+        );
+        KERNEL_STACK_REGION_BEGIN.store(
+            KERNEL_STACK_WINDOW + random_slide(KERNEL_STACK_REGION_SIZE),
+            Ordering::Relaxed,
+        );
+        VMALLOC_BEGIN.store(
+            VMALLOC_WINDOW + random_slide(VMALLOC_SIZE),
+            Ordering::Relaxed,
+        );
+    }
+
+    trace!(
+        "Layout: physical map at {:#x}, heap at {:#x}, kernel stacks at {:#x}, vmalloc at {:#x}.",
+        physical_map_begin(),
+        kernel_heap_begin(),
+        kernel_stack_region_begin(),
+        vmalloc_begin()
+    );
+}
+
+// the direct map at `physical_map_begin` is reachable from the bound table from now on
+pub fn switch_physical_map() {
+    ACTIVE_PHYSICAL_MAP.store(physical_map_begin(), Ordering::Relaxed);
+}
+
+pub fn active_physical_map() -> usize {
+    ACTIVE_PHYSICAL_MAP.load(Ordering::Relaxed)
+}
+
+pub fn physical_map_begin() -> usize {
+    PHYSICAL_MAP_BEGIN.load(Ordering::Relaxed)
+}
+
+pub fn kernel_heap_begin() -> usize {
+    KERNEL_HEAP_BEGIN.load(Ordering::Relaxed)
+}
+
+pub fn kernel_heap_end() -> usize {
+    kernel_heap_begin() + KERNEL_HEAP_SIZE
+}
+
+pub fn kernel_stack_region_begin() -> usize {
+    KERNEL_STACK_REGION_BEGIN.load(Ordering::Relaxed)
+}
+
+pub fn vmalloc_begin() -> usize {
+    VMALLOC_BEGIN.load(Ordering::Relaxed)
+}
+
+pub fn random_stack_end() -> usize {
+    APP_STACK_END - random_pages(APP_STACK_RANDOM_PAGES) * FRAME_SIZE
+}
+
+pub fn random_heap_gap() -> usize {
+    random_pages(HEAP_RANDOM_PAGES) * FRAME_SIZE
+}
+
+pub fn random_pie_base() -> usize {
+    PIE_BASE + random_pages(PIE_RANDOM_PAGES) * FRAME_SIZE
+}
+
+// a whole number of pdpt entries, keeping a region of `size` inside its window
+fn random_slide(size: usize) -> usize {
+    let positions = (WINDOW_SIZE - size) / SLIDE_ALIGN + 1;
+    (random_u64() as usize % positions) * SLIDE_ALIGN
+}
+
+fn random_pages(range: usize) -> usize {
+    random_u64() as usize % range
+}
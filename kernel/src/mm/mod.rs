@@ -1,7 +1,13 @@
 #![allow(dead_code)]
-mod allocator;
+pub mod address_space;
+pub mod allocator;
 pub mod definitions;
 pub mod frame_allocator;
+pub mod frame_descriptor;
+#[cfg(feature = "kasan")]
+pub mod kasan;
+pub mod layout;
+pub mod user_access;
 pub mod utils;
-
-pub use utils::INTERRUPTION_STACK;
+pub mod virtual_range;
+pub mod vmalloc;
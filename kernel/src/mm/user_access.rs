@@ -0,0 +1,138 @@
+use core::{marker::PhantomData, mem::MaybeUninit};
+
+use crate::arch::x86_64::usercopy::{copy_user, strncpy_user};
+
+use super::layout::USER_REGION_END;
+
+#[derive(Debug)]
+pub enum UserAccessError {
+    // outside the user region, or not mapped with the needed access
+    Fault,
+    // no nul within the given length
+    TooLong,
+}
+
+// whether [addr, addr + len) lies entirely in the user region
+fn check_range(addr: usize, len: usize) -> Result<(), UserAccessError> {
+    match addr.checked_add(len) {
+        Some(end) if end <= USER_REGION_END => Ok(()),
+        _ => Err(UserAccessError::Fault),
+    }
+}
+
+// a pointer handed over by user mode, only ever dereferenced through the copy routines
+#[derive(Debug, Clone, Copy)]
+pub struct UserPtr<T> {
+    addr: usize,
+    _type: PhantomData<*mut T>,
+}
+
+impl<T: Copy> UserPtr<T> {
+    pub fn new(addr: usize) -> Self {
+        Self {
+            addr,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+
+    // the `idx`th element after this one
+    pub fn add(&self, idx: usize) -> Self {
+        Self::new(self.addr.wrapping_add(idx * size_of::<T>()))
+    }
+
+    pub fn read(&self) -> Result<T, UserAccessError> {
+        let mut value = MaybeUninit::<T>::uninit();
+        copy_from_user(value.as_mut_ptr() as *mut u8, self.addr, size_of::<T>())?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn write(&self, value: &T) -> Result<(), UserAccessError> {
+        copy_to_user(self.addr, value as *const T as *const u8, size_of::<T>())
+    }
+}
+
+// a byte buffer handed over by user mode
+#[derive(Debug, Clone, Copy)]
+pub struct UserSlice {
+    addr: usize,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(addr: usize, len: usize) -> Self {
+        Self { addr, len }
+    }
+
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // `buf` must not be longer than the slice
+    pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), UserAccessError> {
+        if offset + buf.len() > self.len {
+            return Err(UserAccessError::Fault);
+        }
+        copy_from_user(buf.as_mut_ptr(), self.addr + offset, buf.len())
+    }
+
+    pub fn write(&self, offset: usize, buf: &[u8]) -> Result<(), UserAccessError> {
+        if offset + buf.len() > self.len {
+            return Err(UserAccessError::Fault);
+        }
+        copy_to_user(self.addr + offset, buf.as_ptr(), buf.len())
+    }
+
+    // feeds the slice to `f` in pieces no longer than `N`
+    pub fn for_each_chunk<const N: usize>(
+        &self,
+        mut f: impl FnMut(&[u8]),
+    ) -> Result<(), UserAccessError> {
+        let mut buf = [0u8; N];
+        let mut offset = 0;
+        while offset < self.len {
+            let size = (self.len - offset).min(N);
+            self.read(offset, &mut buf[..size])?;
+            f(&buf[..size]);
+            offset += size;
+        }
+        Ok(())
+    }
+}
+
+pub fn copy_from_user(dst: *mut u8, src: usize, len: usize) -> Result<(), UserAccessError> {
+    check_range(src, len)?;
+    unsafe { copy_user(dst, src as *const u8, len) }.map_err(|_| UserAccessError::Fault)
+}
+
+pub fn copy_to_user(dst: usize, src: *const u8, len: usize) -> Result<(), UserAccessError> {
+    check_range(dst, len)?;
+    unsafe { copy_user(dst as *mut u8, src, len) }.map_err(|_| UserAccessError::Fault)
+}
+
+// copies a nul-terminated string into `buf`, returning its length without the nul
+pub fn strncpy_from_user(buf: &mut [u8], src: UserPtr<u8>) -> Result<usize, UserAccessError> {
+    // only read up to the end of the user region
+    let max = buf.len().min(USER_REGION_END.saturating_sub(src.addr()));
+    let len = unsafe { strncpy_user(buf.as_mut_ptr(), src.addr() as *const u8, max) }
+        .ok_or(UserAccessError::Fault)?;
+    if len < max {
+        Ok(len)
+    } else if max == buf.len() {
+        Err(UserAccessError::TooLong)
+    } else {
+        // ran into the end of the user region
+        Err(UserAccessError::Fault)
+    }
+}
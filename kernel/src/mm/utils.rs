@@ -5,27 +5,31 @@ use crate::{
         mm::page_table::PageTable as ArchPageTable, x86_64::utils::get_current_page_table_frame,
     },
     mm::{
-        definitions::{FRAME_SIZE, FrameAllocator, KERNEL_HEAP_BEGIN, KERNEL_HEAP_SIZE, PageFlags},
+        allocator::init_heap,
+        definitions::{FRAME_SIZE, FrameAllocError, FrameAllocator, PageFlags, PageRegion},
         frame_allocator::FRAME_ALLOCATOR,
+        layout::{
+            KERNEL_HEAP_SIZE, KERNEL_ISTACK_END, PHYSICAL_MAP_SIZE, active_physical_map,
+            init_layout, kernel_heap_begin, physical_map_begin, switch_physical_map,
+        },
     },
     sync::SpinLock,
     trace,
 };
 
 use super::definitions::{
-    BSS_SIZE, BSS_START, DATA_SIZE, DATA_START, Frame, FrameRegion, MappingRegion,
-    PHYSICAL_MAP_BEGIN, PageTable, PhysAddress, RODATA_SIZE, RODATA_START, TEXT_SIZE, TEXT_START,
-    VirtAddress,
+    BSS_SIZE, BSS_START, DATA_SIZE, DATA_START, Frame, MappingRegion, PageTable, PhysAddress,
+    RODATA_SIZE, RODATA_START, TEXT_SIZE, TEXT_START, VirtAddress,
 };
 
 pub fn calculate_phys_addr_from_pptr<T>(addr: *mut T) -> PhysAddress {
     let u = addr as usize;
-    PhysAddress::new(u - PHYSICAL_MAP_BEGIN)
+    PhysAddress::new(u - active_physical_map())
 }
 
 pub fn calculate_pptr_from_phys_addr<T>(addr: PhysAddress) -> *mut T {
     let u = addr.as_usize();
-    (u + PHYSICAL_MAP_BEGIN) as *mut T
+    (u + active_physical_map()) as *mut T
 }
 
 pub unsafe fn borrow_from_phys_addr_mut<T>(addr: PhysAddress) -> &'static mut T {
@@ -33,7 +37,11 @@ pub unsafe fn borrow_from_phys_addr_mut<T>(addr: PhysAddress) -> &'static mut T
 }
 
 lazy_static! {
-    pub static ref INTERRUPTION_STACK: Frame = FRAME_ALLOCATOR.lock().alloc(2).unwrap().start();
+    pub static ref INTERRUPTION_STACK: Frame = FRAME_ALLOCATOR
+        .lock()
+        .alloc(2)
+        .expect("Cannot allocate interruption stack.")
+        .start();
 }
 
 #[derive(Clone)]
@@ -46,17 +54,15 @@ pub struct KernelMappingInfo {
 
 static INITIAL_PAGE_TABLE: SpinLock<Option<ArchPageTable>> = SpinLock::new(None);
 
-pub static KERNEL_MAPPING_INFO: SpinLock<Option<KernelMappingInfo>> = SpinLock::new(None);
+// owns the kernel half, every other table copies its top-level entries
+static KERNEL_PAGE_TABLE: SpinLock<Option<ArchPageTable>> = SpinLock::new(None);
 
-lazy_static! {
-    pub static ref KERNEL_HEAP: FrameRegion = FRAME_ALLOCATOR
-        .lock()
-        .alloc(KERNEL_HEAP_SIZE / FRAME_SIZE)
-        .unwrap();
-}
+pub static KERNEL_MAPPING_INFO: SpinLock<Option<KernelMappingInfo>> = SpinLock::new(None);
 
-pub fn init_mm() {
+// `phys_end` is the end of the highest usable region, the direct map covers everything below
+pub fn init_mm(phys_end: PhysAddress) {
     trace!("Initializing mm...");
+    init_layout();
     let mut ipt = INITIAL_PAGE_TABLE.lock();
 
     *ipt = Some(ArchPageTable::from(unsafe {
@@ -106,18 +112,113 @@ pub fn init_mm() {
         });
     }
 
-    ipt.as_mut().unwrap().map(
-        &MappingRegion {
-            phys_begin: KERNEL_HEAP.start(),
-            virt_begin: VirtAddress::new(KERNEL_HEAP_BEGIN).get_page(),
-            num: KERNEL_HEAP_SIZE / FRAME_SIZE,
-        },
-        PageFlags::Writable,
-    );
+    init_kernel_page_table(kmi.as_ref().unwrap(), phys_end);
+
+    // the boot table keeps its own kernel half, but needs our direct map and heap
+    let master = KERNEL_PAGE_TABLE.lock();
+    let ipt = ipt.as_mut().unwrap();
+    for (begin, size) in [
+        (physical_map_begin(), PHYSICAL_MAP_SIZE),
+        (kernel_heap_begin(), KERNEL_HEAP_SIZE),
+        #[cfg(feature = "kasan")]
+        (
+            super::layout::KASAN_SHADOW_BEGIN,
+            super::layout::KASAN_SHADOW_SIZE,
+        ),
+    ] {
+        ipt.share_from(
+            master.as_ref().unwrap(),
+            &PageRegion::new(VirtAddress::new(begin).get_page(), size / FRAME_SIZE),
+        );
+    }
+    drop(master);
 
     unsafe {
-        ipt.as_ref().unwrap().bind();
+        ipt.bind();
+    }
+    switch_physical_map();
+    init_heap();
+}
+
+fn init_kernel_page_table(kmi: &KernelMappingInfo, phys_end: PhysAddress) {
+    let mut result = ArchPageTable::new().expect("Cannot allocate kernel page table.");
+    result
+        .populate_kernel_half()
+        .expect("Cannot allocate kernel page table.");
+
+    // 1. kernel regions
+    let regions = [
+        (&kmi.text, PageFlags::Executable | PageFlags::Global),
+        (&kmi.rodata, PageFlags::Global),
+        (&kmi.data, PageFlags::Writable | PageFlags::Global),
+        (&kmi.bss, PageFlags::Writable | PageFlags::Global),
+    ];
+
+    for region in regions {
+        result
+            .map(region.0, region.1)
+            .expect("Cannot map kernel regions.");
     }
+
+    // 2. phys region, up to the highest usable frame
+    let huge_page = 1 << 9;
+    result
+        .map(
+            &MappingRegion {
+                phys_begin: Frame::zero(),
+                virt_begin: VirtAddress::new(physical_map_begin()).get_page(),
+                num: phys_end
+                    .as_usize()
+                    .div_ceil(FRAME_SIZE)
+                    .next_multiple_of(huge_page)
+                    .min(PHYSICAL_MAP_SIZE / FRAME_SIZE),
+            },
+            PageFlags::Writable | PageFlags::Huge | PageFlags::Global,
+        )
+        .expect("Cannot map physical memory.");
+
+    // 3. int stack, 8K
+    let istack_region_begin = VirtAddress::new(KERNEL_ISTACK_END - FRAME_SIZE - 1).get_page();
+    result
+        .map(
+            &MappingRegion {
+                phys_begin: *INTERRUPTION_STACK,
+                virt_begin: istack_region_begin,
+                num: 2,
+            },
+            PageFlags::Writable | PageFlags::Global,
+        )
+        .expect("Cannot map interruption stack.");
+
+    *KERNEL_PAGE_TABLE.lock() = Some(result);
+}
+
+// links the whole kernel half into `pt`
+pub fn share_kernel(pt: &mut ArchPageTable) {
+    pt.share_kernel_half(KERNEL_PAGE_TABLE.lock().as_ref().unwrap());
+}
+
+// maps `region` into the kernel half, visible to every address space at once
+pub fn map_kernel(region: &MappingRegion, flags: PageFlags) -> Result<(), FrameAllocError> {
+    KERNEL_PAGE_TABLE
+        .lock()
+        .as_mut()
+        .unwrap()
+        .map(region, flags | PageFlags::Global)
+}
+
+pub fn unmap_kernel(region: &PageRegion) {
+    // only the physical map uses huge pages
+    KERNEL_PAGE_TABLE
+        .lock()
+        .as_mut()
+        .unwrap()
+        .unmap(region)
+        .expect("Kernel mappings outside the physical map are never huge.");
+}
+
+pub fn dump_kernel(region: &PageRegion) {
+    KERNEL_PAGE_TABLE.lock().as_ref().unwrap().dump(region);
 }
 
 pub fn free_initial_page_table() {
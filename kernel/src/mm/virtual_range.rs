@@ -0,0 +1,43 @@
+use alloc::{vec, vec::Vec};
+
+use super::definitions::PageRegion;
+
+// hands out page ranges inside a fixed window, first fit
+pub struct VirtualRangeAllocator {
+    // sorted by start, adjacent ranges are merged
+    free: Vec<PageRegion>,
+}
+
+impl VirtualRangeAllocator {
+    pub fn new(window: PageRegion) -> Self {
+        Self { free: vec![window] }
+    }
+
+    pub fn alloc(&mut self, num: usize) -> Option<PageRegion> {
+        let idx = self.free.iter().position(|x| x.size() >= num)?;
+        let run = self.free[idx].clone();
+        if run.size() == num {
+            self.free.remove(idx);
+        } else {
+            self.free[idx] = PageRegion::new(run.start().offset(num as isize), run.size() - num);
+        }
+        Some(PageRegion::new(run.start(), num))
+    }
+
+    pub fn free(&mut self, region: PageRegion) {
+        let idx = self.free.partition_point(|x| x.start() < region.start());
+        self.free.insert(idx, region);
+        if idx + 1 < self.free.len() && self.free[idx].end() == self.free[idx + 1].start() {
+            let next = self.free.remove(idx + 1);
+            self.free[idx] =
+                PageRegion::new(self.free[idx].start(), self.free[idx].size() + next.size());
+        }
+        if idx > 0 && self.free[idx - 1].end() == self.free[idx].start() {
+            let run = self.free.remove(idx);
+            self.free[idx - 1] = PageRegion::new(
+                self.free[idx - 1].start(),
+                self.free[idx - 1].size() + run.size(),
+            );
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use core::{marker::PhantomData, ptr::NonNull};
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+
+use crate::sync::SpinLock;
+
+use super::{
+    definitions::{
+        FRAME_SIZE, Frame, FrameAllocator, FrameRegion, MappingRegion, PageFlags, PageRegion,
+        PhysAddress, VirtAddress,
+    },
+    frame_allocator::FRAME_ALLOCATOR,
+    frame_descriptor::FrameOwner,
+    layout::{VMALLOC_SIZE, vmalloc_begin},
+    utils::{map_kernel, unmap_kernel},
+    virtual_range::VirtualRangeAllocator,
+};
+
+lazy_static! {
+    static ref VMALLOC_RANGES: SpinLock<VirtualRangeAllocator> =
+        SpinLock::new(VirtualRangeAllocator::new(PageRegion::new(
+            VirtAddress::new(vmalloc_begin()).get_page(),
+            VMALLOC_SIZE / FRAME_SIZE,
+        )));
+}
+
+#[derive(Debug)]
+pub enum VmallocError {
+    OutOfVirtualSpace,
+    OutOfMemory,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteCombining,
+    WriteThrough,
+    Uncached,
+}
+
+impl CacheMode {
+    fn flags(self) -> PageFlags {
+        match self {
+            CacheMode::WriteBack => PageFlags::empty(),
+            CacheMode::WriteCombining => PageFlags::WriteCombining,
+            CacheMode::Uncached => PageFlags::Uncached,
+            CacheMode::WriteThrough => PageFlags::WriteThrough,
+        }
+    }
+}
+
+// every range gets an unmapped page above it, so overruns fault instead of hitting a neighbour
+fn alloc_range(pages: usize) -> Result<PageRegion, VmallocError> {
+    let range = VMALLOC_RANGES
+        .lock()
+        .alloc(pages + 1)
+        .ok_or(VmallocError::OutOfVirtualSpace)?;
+    Ok(PageRegion::new(range.start(), pages))
+}
+
+fn free_range(range: &PageRegion) {
+    // unmapping invalidates every page, global ones included
+    unmap_kernel(range);
+    VMALLOC_RANGES
+        .lock()
+        .free(PageRegion::new(range.start(), range.size() + 1));
+}
+
+// virtually contiguous kernel memory, backed by frames from anywhere
+pub struct VmallocArea {
+    range: PageRegion,
+    frames: Vec<Frame>,
+}
+
+pub fn vmalloc(size: usize) -> Result<VmallocArea, VmallocError> {
+    let pages = size.div_ceil(FRAME_SIZE).max(1);
+    let range = alloc_range(pages)?;
+    let mut area = VmallocArea {
+        range,
+        frames: Vec::with_capacity(pages),
+    };
+
+    // dropping a partial area gives back whatever was mapped so far
+    for i in 0..pages {
+        let frame = FRAME_ALLOCATOR
+            .lock()
+            .alloc_for(1, FrameOwner::Kernel)
+            .map_err(|_| VmallocError::OutOfMemory)?
+            .start();
+        let mapped = map_kernel(
+            &MappingRegion {
+                phys_begin: frame,
+                virt_begin: area.range.start().offset(i as isize),
+                num: 1,
+            },
+            PageFlags::Writable,
+        );
+        if mapped.is_err() {
+            FRAME_ALLOCATOR
+                .lock()
+                .free(&FrameRegion::new(frame, 1))
+                .unwrap();
+            return Err(VmallocError::OutOfMemory);
+        }
+        area.frames.push(frame);
+    }
+    Ok(area)
+}
+
+impl VmallocArea {
+    pub fn as_ptr(&self) -> *mut u8 {
+        let begin: VirtAddress = self.range.start().into();
+        begin.as_usize() as *mut u8
+    }
+
+    pub fn size(&self) -> usize {
+        self.range.size() * FRAME_SIZE
+    }
+}
+
+impl Drop for VmallocArea {
+    fn drop(&mut self) {
+        free_range(&self.range);
+        for frame in &self.frames {
+            FRAME_ALLOCATOR
+                .lock()
+                .free(&FrameRegion::new(*frame, 1))
+                .unwrap();
+        }
+    }
+}
+
+// device memory mapped into the vmalloc window, the frames are not ours to free
+pub struct IoMapping {
+    range: PageRegion,
+    // of `phys` inside its first page
+    offset: usize,
+    len: usize,
+}
+
+pub fn ioremap(phys: PhysAddress, len: usize, mode: CacheMode) -> Result<IoMapping, VmallocError> {
+    let offset = phys.as_usize() % FRAME_SIZE;
+    let pages = (offset + len).div_ceil(FRAME_SIZE).max(1);
+    let range = alloc_range(pages)?;
+    let mapped = map_kernel(
+        &MappingRegion {
+            phys_begin: phys.get_frame(),
+            virt_begin: range.start(),
+            num: pages,
+        },
+        PageFlags::Writable | mode.flags(),
+    );
+    if mapped.is_err() {
+        free_range(&range);
+        return Err(VmallocError::OutOfMemory);
+    }
+    Ok(IoMapping { range, offset, len })
+}
+
+impl IoMapping {
+    pub fn base(&self) -> *mut u8 {
+        let begin: VirtAddress = self.range.start().into();
+        (begin.as_usize() + self.offset) as *mut u8
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // a register of type `T` at `offset` bytes into the mapping
+    pub fn register<T: Copy>(&self, offset: usize) -> Register<'_, T> {
+        assert!(
+            offset + size_of::<T>() <= self.len && offset % align_of::<T>() == 0,
+            "Register at {:#x} outside of the mapping.",
+            offset
+        );
+        Register {
+            ptr: NonNull::new(unsafe { self.base().add(offset) } as *mut T).unwrap(),
+            _mapping: PhantomData,
+        }
+    }
+}
+
+impl Drop for IoMapping {
+    fn drop(&mut self) {
+        free_range(&self.range);
+    }
+}
+
+// every access goes to the device, the compiler may not merge or elide them
+pub struct Register<'a, T: Copy> {
+    ptr: NonNull<T>,
+    _mapping: PhantomData<&'a IoMapping>,
+}
+
+impl<T: Copy> Register<'_, T> {
+    pub fn read(&self) -> T {
+        unsafe { self.ptr.as_ptr().read_volatile() }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { self.ptr.as_ptr().write_volatile(value) }
+    }
+
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
@@ -1,16 +1,18 @@
 use bitflags::bitflags;
 
-use crate::{
-    arch::mm::page_table::PageTable as ArchPageTable,
-    mm::{
-        definitions::{
-            FRAME_SIZE, FrameAllocator, MappingRegion, PageFlags, PageTable, VirtAddress,
-        },
-        frame_allocator::FRAME_ALLOCATOR,
-        utils::borrow_from_phys_addr_mut,
-    },
+use crate::mm::{
+    address_space::{AddressSpace, AreaError},
+    definitions::{FRAME_SIZE, MappingRegion, PageFlags, VirtAddress},
+    frame_allocator::FRAME_ALLOCATOR,
+    frame_descriptor::FrameOwner,
+    layout::{random_heap_gap, random_pie_base},
+    utils::borrow_from_phys_addr_mut,
 };
 
+use super::task::TaskError;
+
+const ET_DYN: u16 = 3;
+
 #[repr(C)]
 #[derive(Debug)]
 struct ElfHeader {
@@ -52,6 +54,15 @@ struct ProgramHeader {
     align: u64,
 }
 
+// where the loader put a program, as the auxiliary vector tells it
+pub struct LoadedElf {
+    pub entry: usize,
+    // the program headers as mapped in memory, 0 if no segment covers them
+    pub phdr: usize,
+    pub phent: usize,
+    pub phnum: usize,
+}
+
 pub trait Readable {
     fn read(&self, dst: *mut u8, offset: usize, size: usize) -> Result<(), ReadError>;
 }
@@ -66,7 +77,7 @@ pub struct MemoryReader {
     size: usize,
 }
 
-pub fn load_elf<R: Readable>(reader: R, pt: &mut ArchPageTable) -> u64 {
+pub fn load_elf<R: Readable>(reader: R, space: &mut AddressSpace) -> Result<LoadedElf, TaskError> {
     let mut header: ElfHeader = unsafe { core::mem::zeroed() };
     reader
         .read(
@@ -76,6 +87,14 @@ pub fn load_elf<R: Readable>(reader: R, pt: &mut ArchPageTable) -> u64 {
         )
         .expect("Failure reading elf header.");
 
+    // position independent programs go to a random base, others to their link address
+    let bias = if header.tybe == ET_DYN {
+        random_pie_base() as u64
+    } else {
+        0
+    };
+    let mut program_end = 0;
+    let mut phdr = 0;
     for ph_idx in 0..header.phnum {
         let ph_offset = header.phoff as u64 + ph_idx as u64 * header.phentsize as u64;
         let mut ph: ProgramHeader = unsafe { core::mem::zeroed() };
@@ -91,10 +110,19 @@ pub fn load_elf<R: Readable>(reader: R, pt: &mut ArchPageTable) -> u64 {
             continue;
         }
 
+        let vaddr = ph.vaddr + bias;
+        if ph.offset <= header.phoff && header.phoff < ph.offset + ph.filesz {
+            phdr = (header.phoff - ph.offset + vaddr) as usize;
+        }
+        let page_offset = vaddr as usize % FRAME_SIZE;
+        program_end = program_end.max((vaddr + ph.memsz) as usize);
         let frames = FRAME_ALLOCATOR
             .lock()
-            .alloc((ph.memsz as usize + FRAME_SIZE - 1) / FRAME_SIZE)
-            .expect("Cannot allocate frames for program.");
+            .alloc_for(
+                (page_offset + ph.memsz as usize).div_ceil(FRAME_SIZE),
+                FrameOwner::UserAnonymous,
+            )
+            .map_err(|_| TaskError::OutOfMemory)?;
 
         let mut flags = PageFlags::Usermode;
         if ph.flags.contains(ProgramFlags::Executable) {
@@ -107,34 +135,53 @@ pub fn load_elf<R: Readable>(reader: R, pt: &mut ArchPageTable) -> u64 {
             // nothing
         }
 
-        pt.map(
+        let mapped = space.map_populated(
             &MappingRegion {
                 phys_begin: frames.start(),
-                virt_begin: VirtAddress::new(ph.vaddr as usize).get_page(),
+                virt_begin: VirtAddress::new(vaddr as usize).get_page(),
                 num: frames.size(),
             },
             flags,
         );
+        if let Err(error) = mapped {
+            // user frames only ever go back through `release`
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            for idx in 0..frames.size() {
+                allocator
+                    .release(frames.start().offset(idx as isize))
+                    .unwrap();
+            }
+            drop(allocator);
+            return Err(match error {
+                AreaError::OutOfMemory => TaskError::OutOfMemory,
+                _ => TaskError::InvalidProgram,
+            });
+        }
 
         unsafe {
             reader
                 .read(
-                    (borrow_from_phys_addr_mut(frames.start().into()) as *mut u8)
-                        .offset(ph.vaddr as isize % FRAME_SIZE as isize),
+                    (borrow_from_phys_addr_mut(frames.start().into()) as *mut u8).add(page_offset),
                     ph.offset as usize,
                     ph.filesz as usize,
                 )
                 .expect("Failure reading program segments.");
             core::ptr::write_bytes(
                 (borrow_from_phys_addr_mut(frames.start().into()) as *mut u8)
-                    .offset(ph.filesz as isize + ph.vaddr as isize % FRAME_SIZE as isize),
+                    .offset(ph.filesz as isize + page_offset as isize),
                 0,
                 (ph.memsz - ph.filesz) as usize,
             );
         }
     }
 
-    header.entry
+    space.init_break(VirtAddress::new(program_end + random_heap_gap()));
+    Ok(LoadedElf {
+        entry: (header.entry + bias) as usize,
+        phdr,
+        phent: header.phentsize as usize,
+        phnum: header.phnum as usize,
+    })
 }
 
 impl MemoryReader {
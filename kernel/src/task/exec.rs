@@ -0,0 +1,131 @@
+use alloc::vec::Vec;
+
+use crate::{
+    INIT_PROGRAM,
+    arch::x86_64::utils::random_u64,
+    mm::{
+        address_space::AddressSpace,
+        definitions::{FRAME_SIZE, VirtAddress},
+        layout::random_stack_end,
+    },
+    task::{
+        elf::{LoadedElf, Readable, load_elf},
+        process::Process,
+        task::TaskError,
+    },
+};
+
+pub const INIT_PATH: &[u8] = b"/init";
+
+// the programs built into the kernel, by the path they are run with
+static PROGRAMS: &[(&[u8], &[u8])] = &[(INIT_PATH, INIT_PROGRAM)];
+
+const AT_NULL: usize = 0;
+const AT_PHDR: usize = 3;
+const AT_PHENT: usize = 4;
+const AT_PHNUM: usize = 5;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+const AT_RANDOM: usize = 25;
+
+// bytes of randomness AT_RANDOM points at
+const RANDOM_SIZE: usize = 16;
+
+// the strings a program is started with, without their nuls
+#[derive(Debug, Default)]
+pub struct ProgramArgs {
+    pub argv: Vec<Vec<u8>>,
+    pub envp: Vec<Vec<u8>>,
+}
+
+// a program loaded into a new address space with its stack set up, ready to be entered
+pub struct Image {
+    pub address_space: AddressSpace,
+    pub entry: usize,
+    pub stack: usize,
+}
+
+pub fn find_program(path: &[u8]) -> Option<&'static [u8]> {
+    PROGRAMS
+        .iter()
+        .find(|(name, _)| *name == path)
+        .map(|(_, program)| *program)
+}
+
+impl ProgramArgs {
+    // what the strings take up on the stack, nuls included
+    pub fn size(&self) -> usize {
+        self.strings().map(|x| x.len() + 1).sum()
+    }
+
+    fn strings(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.argv.iter().chain(self.envp.iter())
+    }
+}
+
+// everything built so far is dropped again if a step fails
+pub fn load_image<R: Readable>(elf_file: R, args: &ProgramArgs) -> Result<Image, TaskError> {
+    let mut address_space = AddressSpace::new(Process::create_page_table()?);
+    let stack_end = random_stack_end();
+    Process::create_stack_areas(&mut address_space, stack_end);
+    let elf = load_elf(elf_file, &mut address_space)?;
+    let stack = write_initial_stack(&mut address_space, stack_end, args, &elf)?;
+    Ok(Image {
+        address_space,
+        entry: elf.entry,
+        stack,
+    })
+}
+
+// the sysv layout, upwards from the returned stack pointer: argc, argv, envp and auxv, the
+// arrays each ended by a null entry, then the strings and the AT_RANDOM bytes at the top
+fn write_initial_stack(
+    space: &mut AddressSpace,
+    stack_end: usize,
+    args: &ProgramArgs,
+    elf: &LoadedElf,
+) -> Result<usize, TaskError> {
+    let random = stack_end - RANDOM_SIZE;
+    let strings = random - args.size();
+    let auxv = [
+        (AT_PHDR, elf.phdr),
+        (AT_PHENT, elf.phent),
+        (AT_PHNUM, elf.phnum),
+        (AT_PAGESZ, FRAME_SIZE),
+        (AT_ENTRY, elf.entry),
+        (AT_RANDOM, random),
+        (AT_NULL, 0),
+    ];
+    let words = 1 + (args.argv.len() + 1) + (args.envp.len() + 1) + auxv.len() * 2;
+    // the abi wants it 16 byte aligned at entry
+    let sp = (strings - words * size_of::<usize>()) & !15;
+
+    let mut stack = Vec::with_capacity(stack_end - sp);
+    let push = |stack: &mut Vec<u8>, value: usize| stack.extend_from_slice(&value.to_ne_bytes());
+    push(&mut stack, args.argv.len());
+    let mut string = strings;
+    for list in [&args.argv, &args.envp] {
+        for arg in list {
+            push(&mut stack, string);
+            string += arg.len() + 1;
+        }
+        push(&mut stack, 0);
+    }
+    for (key, value) in auxv {
+        push(&mut stack, key);
+        push(&mut stack, value);
+    }
+    stack.resize(strings - sp, 0);
+    for arg in args.strings() {
+        stack.extend_from_slice(arg);
+        stack.push(0);
+    }
+    for _ in 0..RANDOM_SIZE / size_of::<u64>() {
+        stack.extend_from_slice(&random_u64().to_ne_bytes());
+    }
+
+    space
+        .write(VirtAddress::new(sp), &stack)
+        .map_err(|_| TaskError::OutOfMemory)?;
+    Ok(sp)
+}
@@ -0,0 +1,79 @@
+use lazy_static::lazy_static;
+
+use crate::{
+    mm::{
+        definitions::{
+            FRAME_SIZE, FrameAllocator, FrameRegion, MappingRegion, PageFlags, PageRegion,
+            VirtAddress,
+        },
+        frame_allocator::FRAME_ALLOCATOR,
+        layout::{KERNEL_STACK_REGION_SIZE, KERNEL_STACK_SIZE, kernel_stack_region_begin},
+        utils::{map_kernel, unmap_kernel},
+        virtual_range::VirtualRangeAllocator,
+    },
+    sync::SpinLock,
+};
+
+use super::task::TaskError;
+
+lazy_static! {
+    static ref KERNEL_STACK_RANGES: SpinLock<VirtualRangeAllocator> =
+        SpinLock::new(VirtualRangeAllocator::new(PageRegion::new(
+            VirtAddress::new(kernel_stack_region_begin()).get_page(),
+            KERNEL_STACK_REGION_SIZE / FRAME_SIZE,
+        )));
+}
+
+// a task's kernel stack, the lowest page of its range is never mapped
+pub struct KernelStack {
+    range: PageRegion,
+    frames: FrameRegion,
+}
+
+impl KernelStack {
+    pub fn new() -> Result<Self, TaskError> {
+        let pages = KERNEL_STACK_SIZE / FRAME_SIZE;
+        let range = KERNEL_STACK_RANGES
+            .lock()
+            .alloc(pages + 1)
+            .ok_or(TaskError::OutOfMemory)?;
+        let Ok(frames) = FRAME_ALLOCATOR.lock().alloc(pages) else {
+            KERNEL_STACK_RANGES.lock().free(range);
+            return Err(TaskError::OutOfMemory);
+        };
+
+        let mapped = map_kernel(
+            &MappingRegion {
+                phys_begin: frames.start(),
+                virt_begin: range.start().offset(1),
+                num: pages,
+            },
+            PageFlags::Writable,
+        );
+        if mapped.is_err() {
+            FRAME_ALLOCATOR.lock().free(&frames).unwrap();
+            KERNEL_STACK_RANGES.lock().free(range);
+            return Err(TaskError::OutOfMemory);
+        }
+        Ok(Self { range, frames })
+    }
+
+    pub fn end(&self) -> usize {
+        let end: VirtAddress = self.range.end().into();
+        end.as_usize()
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        unmap_kernel(&self.range);
+        FRAME_ALLOCATOR.lock().free(&self.frames).unwrap();
+        KERNEL_STACK_RANGES.lock().free(self.range.clone());
+    }
+}
+
+// whether `addr` lies in the kernel stack region, where everything unmapped is a guard
+pub fn is_kernel_stack_address(addr: usize) -> bool {
+    let begin = kernel_stack_region_begin();
+    begin <= addr && addr < begin + KERNEL_STACK_REGION_SIZE
+}
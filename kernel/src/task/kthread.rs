@@ -0,0 +1,19 @@
+#![allow(dead_code)]
+
+use alloc::boxed::Box;
+
+use super::{
+    task::TaskError,
+    task_mgr::{TASK_MANAGER, exit_current_thread},
+};
+
+// runs `f` on a new task in kernel mode, returning its id. it shares the kernel address space,
+// is scheduled like any other task and may block or sleep. it exits when `f` returns
+pub fn spawn(name: &str, f: impl FnOnce() + Send + 'static) -> Result<usize, TaskError> {
+    TASK_MANAGER.lock().add_kernel_thread(name, Box::new(f))
+}
+
+// ends the calling kernel thread, anything it holds on its stack is leaked
+pub fn exit() -> ! {
+    exit_current_thread(0)
+}
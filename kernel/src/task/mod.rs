@@ -1,7 +1,23 @@
 mod elf;
+mod exec;
+mod kernel_stack;
+pub mod kthread;
+mod process;
+pub mod sched;
 pub mod task;
 mod task_mgr;
+pub mod timer;
+mod wait_queue;
 
 pub use elf::MemoryReader;
+pub use exec::{Image, ProgramArgs, find_program, load_image};
+pub use kernel_stack::is_kernel_stack_address;
 pub use task::RegisterStore;
-pub use task_mgr::{TASK_MANAGER, init_first_process_and_jump_to, schedule_next_task};
+pub use task::{TaskError, running_task_id};
+pub use task_mgr::{
+    JoinError, OomPolicy, PriorityError, SIGSEGV, SchedError, TASK_MANAGER, WaitError,
+    exec_current_task, exit_current_process, exit_current_thread, exit_if_killed, exit_status,
+    handle_page_fault, init_first_process_and_jump_to, kill_current_process, preempt_current_task,
+    retry_out_of_memory, set_oom_policy, yield_current_task,
+};
+pub use timer::{sleep, timer_tick};
@@ -0,0 +1,121 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{
+    arch::mm::page_table::PageTable as ArchPageTable,
+    mm::{
+        address_space::{AddressSpace, Backing, VirtualMemoryArea},
+        definitions::{FRAME_SIZE, PageFlags, PageRegion, VirtAddress},
+        layout::APP_STACK_SIZE,
+        utils::share_kernel,
+    },
+    sync::{SpinLock, SpinLockNoIrq},
+    task::{task::TaskError, wait_queue::WaitQueue},
+};
+
+// what the threads of a program share, its pid is the id of the thread it started with
+pub struct Process {
+    pid: usize,
+    // 0 once the parent is gone, nobody waits for such a process
+    parent: AtomicUsize,
+    // no irq lock, the page fault handler takes it with interrupts off
+    address_space: SpinLockNoIrq<AddressSpace>,
+    // woken whenever a child exits
+    child_exit: WaitQueue,
+    // ids and exit values of threads nobody joined yet
+    exited_threads: SpinLock<Vec<(usize, usize)>>,
+    // woken whenever one of its threads exits
+    thread_exit: WaitQueue,
+    // the wait status once killed, its threads exit at their next safe point
+    exit_status: AtomicUsize,
+}
+
+const NOT_EXITING: usize = usize::MAX;
+
+impl Process {
+    pub fn new(pid: usize, parent: usize, address_space: AddressSpace) -> Self {
+        Self {
+            pid,
+            parent: AtomicUsize::new(parent),
+            address_space: SpinLockNoIrq::new(address_space),
+            child_exit: WaitQueue::new(),
+            exited_threads: SpinLock::new(Vec::new()),
+            thread_exit: WaitQueue::new(),
+            exit_status: AtomicUsize::new(NOT_EXITING),
+        }
+    }
+
+    // pid 0, owns the kernel threads and has nothing in its user half
+    pub fn new_kernel() -> Result<Self, TaskError> {
+        let address_space = AddressSpace::new(Self::create_page_table()?);
+        Ok(Self::new(0, 0, address_space))
+    }
+
+    // user half empty, kernel half shared with every address space
+    pub fn create_page_table() -> Result<ArchPageTable, TaskError> {
+        let mut result = ArchPageTable::new().map_err(|_| TaskError::OutOfMemory)?;
+        share_kernel(&mut result);
+        Ok(result)
+    }
+
+    // the stack is filled on demand, the page below it is left as a guard
+    pub fn create_stack_areas(address_space: &mut AddressSpace, stack_end: usize) {
+        let stack_begin = VirtAddress::new(stack_end - APP_STACK_SIZE).get_page();
+        address_space
+            .add_area(VirtualMemoryArea::new(
+                PageRegion::new(stack_begin.offset(-1), 1),
+                PageFlags::empty(),
+                Backing::Guard,
+            ))
+            .unwrap();
+        address_space
+            .add_area(VirtualMemoryArea::new(
+                PageRegion::new(stack_begin, APP_STACK_SIZE / FRAME_SIZE),
+                PageFlags::Usermode | PageFlags::Writable,
+                Backing::Anonymous,
+            ))
+            .unwrap();
+    }
+
+    pub fn pid(&self) -> usize {
+        self.pid
+    }
+
+    pub fn parent(&self) -> usize {
+        self.parent.load(Ordering::Relaxed)
+    }
+
+    pub fn set_parent(&self, parent: usize) {
+        self.parent.store(parent, Ordering::Relaxed);
+    }
+
+    pub fn address_space(&self) -> &SpinLockNoIrq<AddressSpace> {
+        &self.address_space
+    }
+
+    pub fn child_exit(&self) -> &WaitQueue {
+        &self.child_exit
+    }
+
+    pub fn thread_exit(&self) -> &WaitQueue {
+        &self.thread_exit
+    }
+
+    pub fn exited_threads(&self) -> &SpinLock<Vec<(usize, usize)>> {
+        &self.exited_threads
+    }
+
+    pub fn exiting(&self) -> Option<usize> {
+        match self.exit_status.load(Ordering::Relaxed) {
+            NOT_EXITING => None,
+            x => Some(x),
+        }
+    }
+
+    // the first status set is the one it exits with, false if it was already exiting
+    pub fn set_exiting(&self, status: usize) -> bool {
+        self.exit_status
+            .compare_exchange(NOT_EXITING, status, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    }
+}
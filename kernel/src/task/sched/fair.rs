@@ -0,0 +1,95 @@
+use alloc::sync::Arc;
+
+use crate::arch::NANOS_PER_TICK;
+
+use super::{NICE_0_WEIGHT, Scheduler, Task, queue::TaskQueue};
+
+// lowest runs first, ties broken by id
+fn order(task: &Task) -> (u64, usize) {
+    (task.sched().lock().vruntime, task.id())
+}
+
+// ready tasks ordered by virtual runtime, which grows slower the lower the nice value
+pub struct FairScheduler {
+    queue: TaskQueue,
+    // never decreases, tasks that were away start from here
+    min_vruntime: u64,
+    // ticks a task runs at least before being preempted
+    slice: u64,
+}
+
+impl FairScheduler {
+    pub const DEFAULT_SLICE: u64 = 3;
+
+    pub const fn new(slice: u64) -> Self {
+        Self {
+            queue: TaskQueue::new(),
+            min_vruntime: 0,
+            slice: if slice == 0 { 1 } else { slice },
+        }
+    }
+
+    fn update_min_vruntime(&mut self, running: Option<u64>) {
+        let leftmost = self.queue.first().map(|x| x.sched().lock().vruntime);
+        let lowest = match (running, leftmost) {
+            (Some(x), Some(y)) => x.min(y),
+            (x, y) => x.or(y).unwrap_or(self.min_vruntime),
+        };
+        self.min_vruntime = self.min_vruntime.max(lowest);
+    }
+}
+
+impl Scheduler for FairScheduler {
+    fn enqueue(&mut self, task: Arc<Task>) {
+        {
+            let mut sched = task.sched().lock();
+            // sleepers get at most one slice of credit, so they cannot hog the cpu on return
+            let credit = self.slice * NANOS_PER_TICK;
+            sched.vruntime = sched.vruntime.max(self.min_vruntime.saturating_sub(credit));
+        }
+        let position = order(&task);
+        self.queue.insert(task, |x| order(x) > position);
+    }
+
+    fn dequeue(&mut self, task: &Task) -> Option<Arc<Task>> {
+        self.queue.remove(task)
+    }
+
+    fn pick_next(&mut self) -> Option<Arc<Task>> {
+        let task = self.queue.pop_front()?;
+        let vruntime = {
+            let mut sched = task.sched().lock();
+            sched.ran = 0;
+            sched.vruntime
+        };
+        self.update_min_vruntime(Some(vruntime));
+        Some(task)
+    }
+
+    fn tick(&mut self, task: Option<&Task>) -> bool {
+        let Some(task) = task else {
+            return !self.queue.is_empty();
+        };
+        let (vruntime, ran) = {
+            let mut sched = task.sched().lock();
+            sched.vruntime += NANOS_PER_TICK * NICE_0_WEIGHT / sched.weight();
+            sched.ran += 1;
+            (sched.vruntime, sched.ran)
+        };
+        self.update_min_vruntime(Some(vruntime));
+        ran >= self.slice
+            && self
+                .queue
+                .first()
+                .is_some_and(|x| x.sched().lock().vruntime < vruntime)
+    }
+
+    // queues it behind every ready task
+    fn yield_task(&mut self, task: &Task) {
+        if let Some(last) = self.queue.last() {
+            let last = last.sched().lock().vruntime;
+            let mut sched = task.sched().lock();
+            sched.vruntime = sched.vruntime.max(last + 1);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use alloc::sync::Arc;
+
+use super::task::Task;
+
+mod fair;
+mod queue;
+mod rt;
+
+pub use fair::FairScheduler;
+pub use rt::{DEADLINE_BANDWIDTH_LIMIT, RT_PRIORITY_MAX, RT_PRIORITY_MIN, RtScheduler};
+
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+// load weight per nice value from -20 to 19, each step is about 10% of cpu time
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+const NICE_0_WEIGHT: u64 = 1024;
+
+// the ready tasks, not including the running one
+pub trait Scheduler: Send {
+    // `task` became ready to run
+    fn enqueue(&mut self, task: Arc<Task>);
+    // takes `task` off the queue, if it was queued
+    fn dequeue(&mut self, task: &Task) -> Option<Arc<Task>>;
+    // takes the task to run next off the queue
+    fn pick_next(&mut self) -> Option<Arc<Task>>;
+    // charges a tick to the running `task`, None while idling. true if it should give up the cpu
+    fn tick(&mut self, task: Option<&Task>) -> bool;
+    // the running `task` gives up the cpu, it is enqueued right after
+    fn yield_task(&mut self, _task: &Task) {}
+}
+
+// all in ticks, runtime <= deadline <= period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineParams {
+    pub runtime: u64,
+    pub deadline: u64,
+    pub period: u64,
+}
+
+impl DeadlineParams {
+    // share of the cpu reserved, in millionths
+    pub fn bandwidth(&self) -> u64 {
+        self.runtime * 1_000_000 / self.period
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    // fair share by nice value
+    Normal,
+    // fixed priority, runs until it blocks or yields
+    Fifo(u8),
+    // fixed priority, takes turns with its priority every slice
+    RoundRobin(u8),
+    // earliest deadline first, with `runtime` reserved every `period`
+    Deadline(DeadlineParams),
+}
+
+impl SchedPolicy {
+    pub fn is_realtime(&self) -> bool {
+        *self != SchedPolicy::Normal
+    }
+
+    pub fn bandwidth(&self) -> u64 {
+        match self {
+            SchedPolicy::Deadline(params) => params.bandwidth(),
+            _ => 0,
+        }
+    }
+}
+
+// per task bookkeeping of the scheduler
+pub struct SchedEntity {
+    pub policy: SchedPolicy,
+    // weighted nanoseconds run, lowest runs first
+    pub vruntime: u64,
+    pub nice: i8,
+    // ticks run since it was last picked
+    pub ran: u64,
+    // absolute deadline and runtime left in the current period, for deadline tasks
+    pub deadline: u64,
+    pub budget: u64,
+    // ran out of budget, waits for the next period
+    pub throttled: bool,
+    // the task behind it on the queue it is on, owned by that queue
+    next: Option<Arc<Task>>,
+}
+
+impl SchedEntity {
+    pub const fn new() -> Self {
+        Self {
+            policy: SchedPolicy::Normal,
+            vruntime: 0,
+            nice: 0,
+            ran: 0,
+            deadline: 0,
+            budget: 0,
+            throttled: false,
+            next: None,
+        }
+    }
+
+    pub fn weight(&self) -> u64 {
+        NICE_TO_WEIGHT[(self.nice - NICE_MIN) as usize]
+    }
+}
+
+// realtime tasks always run ahead of normal ones
+pub struct ClassScheduler {
+    rt: RtScheduler,
+    fair: FairScheduler,
+}
+
+impl ClassScheduler {
+    pub const fn new(slice: u64) -> Self {
+        Self {
+            rt: RtScheduler::new(),
+            fair: FairScheduler::new(slice),
+        }
+    }
+}
+
+fn is_realtime(task: &Task) -> bool {
+    task.sched().lock().policy.is_realtime()
+}
+
+impl Scheduler for ClassScheduler {
+    fn enqueue(&mut self, task: Arc<Task>) {
+        if is_realtime(&task) {
+            self.rt.enqueue(task);
+        } else {
+            self.fair.enqueue(task);
+        }
+    }
+
+    fn dequeue(&mut self, task: &Task) -> Option<Arc<Task>> {
+        if is_realtime(task) {
+            self.rt.dequeue(task)
+        } else {
+            self.fair.dequeue(task)
+        }
+    }
+
+    fn pick_next(&mut self) -> Option<Arc<Task>> {
+        self.rt.pick_next().or_else(|| self.fair.pick_next())
+    }
+
+    fn tick(&mut self, task: Option<&Task>) -> bool {
+        let realtime = task.filter(|x| is_realtime(x));
+        // throttled tasks are only replenished here, so it runs on every tick
+        let rt = self.rt.tick(realtime);
+        if realtime.is_some() {
+            return rt;
+        }
+        let fair = self.fair.tick(task);
+        self.rt.has_ready() || fair
+    }
+
+    fn yield_task(&mut self, task: &Task) {
+        if is_realtime(task) {
+            self.rt.yield_task(task);
+        } else {
+            self.fair.yield_task(task);
+        }
+    }
+}
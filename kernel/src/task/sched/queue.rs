@@ -0,0 +1,78 @@
+use alloc::sync::Arc;
+
+use super::Task;
+
+// tasks linked through their sched entities, so queueing them never allocates and is safe from
+// interrupt handlers. it holds a reference to each, a task is on one queue at most
+pub struct TaskQueue {
+    head: Option<Arc<Task>>,
+}
+
+fn next_of(task: &Task) -> Option<Arc<Task>> {
+    task.sched().lock().next.clone()
+}
+
+impl TaskQueue {
+    pub const fn new() -> Self {
+        Self { head: None }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    pub fn first(&self) -> Option<&Arc<Task>> {
+        self.head.as_ref()
+    }
+
+    pub fn last(&self) -> Option<Arc<Task>> {
+        let mut last = self.head.clone()?;
+        while let Some(next) = next_of(&last) {
+            last = next;
+        }
+        Some(last)
+    }
+
+    // puts `task` in front of the first task `before` holds for, at the end if there is none
+    pub fn insert(&mut self, task: Arc<Task>, before: impl Fn(&Task) -> bool) {
+        let Some(mut prev) = self.head.clone().filter(|x| !before(x)) else {
+            task.sched().lock().next = self.head.take();
+            self.head = Some(task);
+            return;
+        };
+        while let Some(next) = next_of(&prev).filter(|x| !before(x)) {
+            prev = next;
+        }
+        let mut link = prev.sched().lock();
+        task.sched().lock().next = link.next.take();
+        link.next = Some(task);
+    }
+
+    // takes the first task `pred` holds for off the queue
+    pub fn remove_first(&mut self, pred: impl Fn(&Task) -> bool) -> Option<Arc<Task>> {
+        if pred(self.head.as_ref()?) {
+            let task = self.head.take().unwrap();
+            self.head = task.sched().lock().next.take();
+            return Some(task);
+        }
+        let mut prev = self.head.clone().unwrap();
+        loop {
+            let next = next_of(&prev)?;
+            if pred(&next) {
+                let mut link = prev.sched().lock();
+                let task = link.next.take().unwrap();
+                link.next = task.sched().lock().next.take();
+                return Some(task);
+            }
+            prev = next;
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Option<Arc<Task>> {
+        self.remove_first(|_| true)
+    }
+
+    pub fn remove(&mut self, task: &Task) -> Option<Arc<Task>> {
+        self.remove_first(|x| core::ptr::eq(x, task))
+    }
+}
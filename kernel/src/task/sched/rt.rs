@@ -0,0 +1,210 @@
+use alloc::sync::Arc;
+use core::cmp::Reverse;
+
+use crate::{arch::TICKS_PER_SECOND, task::timer::ticks};
+
+use super::{SchedEntity, SchedPolicy, Scheduler, Task, queue::TaskQueue};
+
+pub const RT_PRIORITY_MIN: u8 = 1;
+pub const RT_PRIORITY_MAX: u8 = 99;
+
+// share of the cpu deadline tasks may reserve together, in millionths
+pub const DEADLINE_BANDWIDTH_LIMIT: u64 = 950_000;
+
+// ticks a round robin task runs before the others of its priority get a turn
+const RR_SLICE: u64 = 10;
+
+// fixed priority tasks together run at most the same share of every period, so a busy one
+// cannot starve the normal tasks and the kernel threads among them
+const FIXED_PERIOD: u64 = TICKS_PER_SECOND;
+const FIXED_RUNTIME: u64 = FIXED_PERIOD * DEADLINE_BANDWIDTH_LIMIT / 1_000_000;
+
+// lower runs first, deadline tasks ahead of fixed priority ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Rank {
+    Deadline(u64),
+    Fixed(Reverse<u8>),
+}
+
+fn rank(sched: &SchedEntity) -> Option<Rank> {
+    match sched.policy {
+        SchedPolicy::Deadline(_) => Some(Rank::Deadline(sched.deadline)),
+        SchedPolicy::Fifo(x) | SchedPolicy::RoundRobin(x) => Some(Rank::Fixed(Reverse(x))),
+        SchedPolicy::Normal => None,
+    }
+}
+
+// when a throttled deadline task gets its runtime back
+fn next_period(sched: &SchedEntity) -> u64 {
+    let SchedPolicy::Deadline(params) = sched.policy else {
+        unreachable!()
+    };
+    sched.deadline - params.deadline + params.period
+}
+
+// ready realtime tasks by rank, in turn within one
+pub struct RtScheduler {
+    ready: TaskQueue,
+    // deadline tasks that used up their runtime, until their next period
+    throttled: TaskQueue,
+    // preempted by a better task, goes back to the front of its priority
+    preempted: Option<usize>,
+    // ticks fixed priority tasks ran in the period ending at `period_end`
+    fixed_ran: u64,
+    period_end: u64,
+}
+
+impl RtScheduler {
+    pub const fn new() -> Self {
+        Self {
+            ready: TaskQueue::new(),
+            throttled: TaskQueue::new(),
+            preempted: None,
+            fixed_ran: 0,
+            period_end: 0,
+        }
+    }
+
+    pub fn has_ready(&self) -> bool {
+        self.best_rank().is_some()
+    }
+
+    // fixed priority tasks wait for the next period once they used up their share
+    fn fixed_throttled(&self) -> bool {
+        self.fixed_ran >= FIXED_RUNTIME
+    }
+
+    // of the task that may run next
+    fn best_rank(&self) -> Option<Rank> {
+        self.ready
+            .first()
+            .and_then(|x| rank(&x.sched().lock()))
+            .filter(|x| matches!(x, Rank::Deadline(_)) || !self.fixed_throttled())
+    }
+
+    // behind the tasks of the same rank, or in front of them
+    fn queue(&mut self, task: Arc<Task>, front: bool) {
+        let position = rank(&task.sched().lock());
+        self.ready.insert(task, |x| {
+            let other = rank(&x.sched().lock());
+            if front {
+                other >= position
+            } else {
+                other > position
+            }
+        });
+    }
+
+    // starts the next period of throttled tasks that are due
+    fn replenish(&mut self, now: u64) {
+        while let Some(task) = self
+            .throttled
+            .remove_first(|x| next_period(&x.sched().lock()) <= now)
+        {
+            {
+                let mut sched = task.sched().lock();
+                let SchedPolicy::Deadline(params) = sched.policy else {
+                    unreachable!()
+                };
+                sched.throttled = false;
+                sched.deadline = now + params.deadline;
+                sched.budget = params.runtime;
+            }
+            self.queue(task, false);
+        }
+    }
+}
+
+impl Scheduler for RtScheduler {
+    fn enqueue(&mut self, task: Arc<Task>) {
+        let mut sched = task.sched().lock();
+        match sched.policy {
+            SchedPolicy::Deadline(params) => {
+                if sched.throttled {
+                    drop(sched);
+                    self.throttled.insert(task, |_| true);
+                    return;
+                }
+                // a new period once the deadline passed, or if the runtime left could not be
+                // spent by then without going over the reserved bandwidth
+                let now = ticks();
+                if now >= sched.deadline
+                    || sched.budget * params.deadline > params.runtime * (sched.deadline - now)
+                {
+                    sched.deadline = now + params.deadline;
+                    sched.budget = params.runtime;
+                }
+                drop(sched);
+                self.queue(task, false);
+            }
+            SchedPolicy::Fifo(_) | SchedPolicy::RoundRobin(_) => {
+                drop(sched);
+                let front = self.preempted == Some(task.id());
+                if front {
+                    self.preempted = None;
+                }
+                self.queue(task, front);
+            }
+            SchedPolicy::Normal => panic!("Normal task {} in the realtime class.", task.id()),
+        }
+    }
+
+    fn dequeue(&mut self, task: &Task) -> Option<Arc<Task>> {
+        self.throttled
+            .remove(task)
+            .or_else(|| self.ready.remove(task))
+    }
+
+    fn pick_next(&mut self) -> Option<Arc<Task>> {
+        self.best_rank()?;
+        let task = self.ready.pop_front()?;
+        task.sched().lock().ran = 0;
+        Some(task)
+    }
+
+    fn tick(&mut self, task: Option<&Task>) -> bool {
+        let now = ticks();
+        self.replenish(now);
+        if now >= self.period_end {
+            self.period_end = now + FIXED_PERIOD;
+            self.fixed_ran = 0;
+        }
+        let Some(task) = task else {
+            return self.has_ready();
+        };
+
+        let (rank, expired) = {
+            let mut sched = task.sched().lock();
+            sched.ran += 1;
+            let expired = match sched.policy {
+                SchedPolicy::Deadline(_) => {
+                    sched.budget = sched.budget.saturating_sub(1);
+                    sched.throttled = sched.budget == 0;
+                    sched.throttled
+                }
+                SchedPolicy::RoundRobin(_) => sched.ran >= RR_SLICE,
+                _ => false,
+            };
+            (rank(&sched).unwrap(), expired)
+        };
+        if let Rank::Fixed(_) = rank {
+            self.fixed_ran += 1;
+        }
+        match (rank, self.best_rank()) {
+            // overrunning deadline tasks stop right away
+            (Rank::Deadline(_), _) if expired => true,
+            (Rank::Fixed(_), _) if self.fixed_throttled() => {
+                self.preempted = Some(task.id());
+                true
+            }
+            (Rank::Fixed(_), Some(best)) if best < rank => {
+                self.preempted = Some(task.id());
+                true
+            }
+            (_, Some(best)) if best < rank => true,
+            // a round robin task lets the others of its priority go first
+            (_, Some(best)) => expired && best == rank,
+            (_, None) => false,
+        }
+    }
+}
@@ -1,137 +1,322 @@
+use alloc::{boxed::Box, string::String, sync::Arc};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering};
+
 use crate::{
     arch::{
-        RegisterStore as ArchRegisterStore, disable_irq,
-        mm::page_table::PageTable as ArchPageTable, x86_64::task::set_structure_base,
+        RegisterStore as ArchRegisterStore, SyscallFrame, disable_irq,
+        x86_64::task::{set_structure_base, set_thread_pointer},
     },
-    mm::{
-        INTERRUPTION_STACK,
-        definitions::{
-            APP_STACK_BEGIN, APP_STACK_END, FRAME_SIZE, Frame, FrameAllocator, KERNEL_HEAP_BEGIN,
-            KERNEL_HEAP_SIZE, KERNEL_ISTACK_END, KERNEL_STACK_BEGIN, MappingRegion,
-            PHYSICAL_MAP_BEGIN, PageFlags, PageTable, VirtAddress,
-        },
-        frame_allocator::FRAME_ALLOCATOR,
-        utils::{KERNEL_HEAP, KERNEL_MAPPING_INFO},
+    mm::{address_space::AddressSpace, definitions::PageTable, layout::KERNEL_ISTACK_END},
+    sync::{SpinLock, SpinLockNoIrq},
+    task::{
+        exec::Image,
+        kernel_stack::KernelStack,
+        process::Process,
+        sched::{SchedEntity, SchedPolicy},
     },
-    task::elf::{Readable, load_elf},
 };
 
 pub trait RegisterStore {
     extern "sysv64" fn switch_to(&self) -> !;
     fn new(pc: usize, sp: usize, ksp: usize) -> Self;
+    fn set_return_value(&mut self, value: usize);
+    // the first argument of the function it starts in
+    fn set_argument(&mut self, value: usize);
+}
+
+#[derive(Debug)]
+pub enum TaskError {
+    OutOfMemory,
+    InvalidProgram,
+    // called with nothing running
+    NoTask,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TaskState {
+    Running,
+    // waiting for its turn
+    Ready,
+    // on a wait queue until woken
+    Blocked,
+    // until a timer wakes it
+    Sleeping,
+    // exited, only kept until nothing runs on it anymore
+    Zombie,
 }
 
+// a thread, what the scheduler runs. everything it shares with other threads is in its process
 #[repr(C)]
 pub struct Task {
     pub registers: ArchRegisterStore,
-    page_table: ArchPageTable,
+    process: Arc<Process>,
+    kernel_stack: KernelStack,
+    // the thread id, the pid for the first thread of a process
     id: usize,
+    // thread pointer for tls, loaded whenever it is switched to
+    fs_base: AtomicU64,
+    state: AtomicU8,
+    // exits at its next safe point, as when its whole process is killed
+    killed: AtomicBool,
+    sched: SpinLockNoIrq<SchedEntity>,
+    kernel_thread: Option<KernelThread>,
 }
 
-impl Task {
-    pub fn new<R: Readable>(id: usize, elf_file: R) -> Self {
-        let mut page_table = Self::create_page_table(id);
-        let entry = load_elf(elf_file, &mut page_table);
-        let registers = ArchRegisterStore::new(
-            entry as usize,
-            APP_STACK_END,
-            KERNEL_STACK_BEGIN + (4 * id) * FRAME_SIZE,
-        );
-        Self {
-            registers,
-            page_table,
-            id,
-        }
-    }
+pub type KernelThreadFn = Box<dyn FnOnce() + Send>;
 
-    fn create_page_table(id: usize) -> ArchPageTable {
-        let mut result = ArchPageTable::new();
+// what only tasks running kernel code have
+struct KernelThread {
+    name: String,
+    // taken once it starts running
+    f: SpinLock<Option<KernelThreadFn>>,
+}
 
-        // 1. kernel regions
-        let kmi = KERNEL_MAPPING_INFO.lock().as_ref().unwrap().clone();
-        let regions = [
-            (kmi.text, PageFlags::Executable),
-            (kmi.rodata, PageFlags::empty()),
-            (kmi.data, PageFlags::Writable),
-            (kmi.bss, PageFlags::Writable),
-        ];
+// id of the task last jumped to, readable without locks from fault handlers
+static RUNNING_TASK: AtomicUsize = AtomicUsize::new(0);
 
-        for region in regions {
-            result.map(&region.0, region.1);
-        }
+pub fn running_task_id() -> usize {
+    RUNNING_TASK.load(Ordering::Relaxed)
+}
 
-        // 2. phys region, 128M
-        result.map(
-            &MappingRegion {
-                phys_begin: Frame::zero(),
-                virt_begin: VirtAddress::new(PHYSICAL_MAP_BEGIN).get_page(),
-                num: 128 * 1024 * 1024 / 4096,
-            },
-            PageFlags::Writable,
-        );
+impl Task {
+    // a new process with a single thread running `image`, both with id `id`
+    pub fn new(id: usize, parent: usize, image: Image) -> Result<Self, TaskError> {
+        let kernel_stack = KernelStack::new()?;
+        let registers = ArchRegisterStore::new(image.entry, image.stack, kernel_stack.end());
+        let process = Process::new(id, parent, image.address_space);
+        Ok(Self::with_process(
+            id,
+            Arc::new(process),
+            registers,
+            kernel_stack,
+            SchedEntity::new(),
+        ))
+    }
 
-        // 3. int stack, 8K
-        let istack_region_begin = VirtAddress::new(KERNEL_ISTACK_END - FRAME_SIZE - 1).get_page();
-        let istack = *INTERRUPTION_STACK;
-        result.map(
-            &MappingRegion {
-                phys_begin: istack,
-                virt_begin: istack_region_begin,
-                num: 2,
-            },
-            PageFlags::Writable,
-        );
+    // runs `entry` in kernel mode on its own kernel stack, in the kernel process. `f` is left
+    // for `entry` to take
+    pub fn new_kernel(
+        id: usize,
+        name: &str,
+        process: Arc<Process>,
+        entry: extern "sysv64" fn() -> !,
+        f: Option<KernelThreadFn>,
+    ) -> Result<Self, TaskError> {
+        let kernel_stack = KernelStack::new()?;
+        // as if `entry` had been called, with a return address pushed
+        let sp = kernel_stack.end() - size_of::<usize>();
+        let registers = ArchRegisterStore::new(entry as usize, sp, kernel_stack.end());
+        let mut task = Self::with_process(id, process, registers, kernel_stack, SchedEntity::new());
+        task.kernel_thread = Some(KernelThread {
+            name: name.into(),
+            f: SpinLock::new(f),
+        });
+        Ok(task)
+    }
 
-        // 4. task kernel stack, 8K
-        let stack_region_begin = VirtAddress::new(KERNEL_STACK_BEGIN)
-            .get_page()
-            .offset(4 * id as isize - 2);
-        let kstack = FRAME_ALLOCATOR.lock().alloc(2).unwrap().start();
-        result.map(
-            &MappingRegion {
-                phys_begin: kstack,
-                virt_begin: stack_region_begin,
-                num: 2,
-            },
-            PageFlags::Writable,
-        );
+    // another thread of the same process, starting at `entry(arg)` on the user stack `stack`
+    pub fn new_thread(
+        &self,
+        id: usize,
+        entry: usize,
+        stack: usize,
+        arg: usize,
+    ) -> Result<Self, TaskError> {
+        let kernel_stack = KernelStack::new()?;
+        let mut registers = ArchRegisterStore::new(entry, stack, kernel_stack.end());
+        registers.set_argument(arg);
+        Ok(Self::with_process(
+            id,
+            self.process.clone(),
+            registers,
+            kernel_stack,
+            self.forked_sched(),
+        ))
+    }
 
-        // 5. kernel heap, 16M
-        let kernel_heap_region_begin = VirtAddress::new(KERNEL_HEAP_BEGIN).get_page();
-        result.map(
-            &MappingRegion {
-                phys_begin: KERNEL_HEAP.start(),
-                virt_begin: kernel_heap_region_begin,
-                num: KERNEL_HEAP_SIZE / FRAME_SIZE,
-            },
-            PageFlags::Writable,
+    // the child is a new process with only this thread, resuming after the syscall in `frame`
+    // with a zero return value
+    pub fn fork(&self, id: usize, frame: &SyscallFrame) -> Result<Self, TaskError> {
+        let kernel_stack = KernelStack::new()?;
+        let page_table = Process::create_page_table()?;
+        let address_space = self
+            .address_space()
+            .lock()
+            .fork(page_table)
+            .map_err(|_| TaskError::OutOfMemory)?;
+        let mut registers = ArchRegisterStore::from_syscall(frame, kernel_stack.end());
+        registers.set_return_value(0);
+        let process = Process::new(id, self.pid(), address_space);
+        let task = Self::with_process(
+            id,
+            Arc::new(process),
+            registers,
+            kernel_stack,
+            self.forked_sched(),
         );
+        task.set_fs_base(self.fs_base());
+        Ok(task)
+    }
 
-        // 6. app stack
-        let stack = FRAME_ALLOCATOR.lock().alloc(1).unwrap().start();
-        result.map(
-            &MappingRegion {
-                phys_begin: stack,
-                virt_begin: VirtAddress::new(APP_STACK_BEGIN).get_page(),
-                num: 1,
-            },
-            PageFlags::Usermode | PageFlags::Writable,
-        );
-        result
+    fn with_process(
+        id: usize,
+        process: Arc<Process>,
+        registers: ArchRegisterStore,
+        kernel_stack: KernelStack,
+        sched: SchedEntity,
+    ) -> Self {
+        Self {
+            registers,
+            process,
+            kernel_stack,
+            id,
+            fs_base: AtomicU64::new(0),
+            state: AtomicU8::new(TaskState::Ready as u8),
+            killed: AtomicBool::new(false),
+            sched: SpinLockNoIrq::new(sched),
+            kernel_thread: None,
+        }
     }
 
     #[inline(always)]
     pub unsafe fn jump_to(&self) -> ! {
         unsafe {
             disable_irq();
+            RUNNING_TASK.store(self.id, Ordering::Relaxed);
             set_structure_base(self as *const Task as u64, false);
+            set_thread_pointer(self.fs_base());
             // temporarily use int stack to continue our rust code
-            self.page_table.bind_and_switch_stack(KERNEL_ISTACK_END);
+            self.address_space()
+                .get_mut()
+                .page_table()
+                .bind_and_switch_stack(KERNEL_ISTACK_END);
             self.registers.switch_to();
         }
     }
 
+    // drops whatever `task` was running and enters user mode at `entry` with stack `stack`, on
+    // the address space its process has now. nothing on the current stack is dropped, so it is
+    // taken as a pointer: the caller must not hold a reference of its own, and the task has to
+    // stay alive, e.g. in the task list, until it is switched away from
+    pub unsafe fn start_over(task: *const Task, entry: usize, stack: usize) -> ! {
+        let task = unsafe { &*task };
+        let registers = ArchRegisterStore::new(entry, stack, task.kernel_stack.end());
+        unsafe {
+            disable_irq();
+            set_structure_base(task as *const Task as u64, false);
+            set_thread_pointer(task.fs_base());
+            registers.switch_to();
+        }
+    }
+
+    // drops whatever the task was running and calls `entry` on its own kernel stack, in the
+    // address space of its process. nothing on the current stack is dropped
+    pub unsafe fn start_in_kernel(&self, entry: extern "sysv64" fn() -> !) -> ! {
+        let sp = self.kernel_stack.end() - size_of::<usize>();
+        let registers = ArchRegisterStore::new(entry as usize, sp, self.kernel_stack.end());
+        unsafe {
+            disable_irq();
+            RUNNING_TASK.store(self.id, Ordering::Relaxed);
+            set_structure_base(self as *const Task as u64, false);
+            set_thread_pointer(self.fs_base());
+            // the current stack is in the kernel half, still mapped after the switch
+            self.address_space().get_mut().page_table().bind();
+            registers.switch_to();
+        }
+    }
+
     pub fn id(&self) -> usize {
         self.id
     }
+
+    pub fn pid(&self) -> usize {
+        self.process.pid()
+    }
+
+    pub fn process(&self) -> &Arc<Process> {
+        &self.process
+    }
+
+    pub fn fs_base(&self) -> u64 {
+        self.fs_base.load(Ordering::Relaxed)
+    }
+
+    // only takes effect when it is switched to next, unless it is loaded as well
+    pub fn set_fs_base(&self, base: u64) {
+        self.fs_base.store(base, Ordering::Relaxed);
+    }
+
+    pub fn state(&self) -> TaskState {
+        match self.state.load(Ordering::Relaxed) {
+            0 => TaskState::Running,
+            1 => TaskState::Ready,
+            2 => TaskState::Blocked,
+            3 => TaskState::Sleeping,
+            _ => TaskState::Zombie,
+        }
+    }
+
+    pub fn set_state(&self, state: TaskState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    // moves a blocked or sleeping task back to ready, false if it was neither. the caller has
+    // to hand it to the scheduler
+    pub fn wake(&self) -> bool {
+        [TaskState::Blocked, TaskState::Sleeping]
+            .into_iter()
+            .any(|x| {
+                self.state
+                    .compare_exchange(
+                        x as u8,
+                        TaskState::Ready as u8,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+            })
+    }
+
+    // the caller has to wake it, so it gets to a safe point
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Relaxed) || self.process.exiting().is_some()
+    }
+
+    // the nice value and a fixed priority are inherited, reserved bandwidth is not
+    fn forked_sched(&self) -> SchedEntity {
+        let parent = self.sched.lock();
+        let policy = match parent.policy {
+            SchedPolicy::Deadline(_) => SchedPolicy::Normal,
+            x => x,
+        };
+        let mut sched = SchedEntity::new();
+        sched.policy = policy;
+        sched.nice = parent.nice;
+        sched
+    }
+
+    pub fn is_kernel_thread(&self) -> bool {
+        self.kernel_thread.is_some()
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.kernel_thread.as_ref().map(|x| x.name.as_str())
+    }
+
+    pub fn take_kernel_fn(&self) -> Option<KernelThreadFn> {
+        self.kernel_thread.as_ref()?.f.lock().take()
+    }
+
+    pub fn sched(&self) -> &SpinLockNoIrq<SchedEntity> {
+        &self.sched
+    }
+
+    pub fn address_space(&self) -> &SpinLockNoIrq<AddressSpace> {
+        self.process.address_space()
+    }
 }
@@ -1,20 +1,83 @@
 #![allow(dead_code)]
 
-use alloc::{collections::LinkedList, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, collections::LinkedList, sync::Arc, vec, vec::Vec};
+use lazy_static::lazy_static;
 
 use crate::{
     INIT_PROGRAM,
-    mm::utils::free_initial_page_table,
+    arch::{SyscallFrame, wait_for_interrupt, x86_64::task::switch_out},
+    mm::{
+        address_space::{AddressSpace, FaultError, FaultFlags},
+        definitions::{PageTable, VirtAddress},
+        utils::free_initial_page_table,
+    },
     sync::{RwLock, SpinLock, SpinLockNoIrq},
-    task::elf::{MemoryReader, Readable},
+    task::elf::MemoryReader,
     trace,
 };
 
-use super::task::Task;
+use super::{
+    exec::{INIT_PATH, Image, ProgramArgs, load_image},
+    process::Process,
+    sched::{
+        ClassScheduler, DEADLINE_BANDWIDTH_LIMIT, FairScheduler, NICE_MAX, NICE_MIN, SchedPolicy,
+        Scheduler,
+    },
+    task::{KernelThreadFn, Task, TaskError, TaskState},
+};
+
+// wait statuses, as returned by waitpid
+pub const SIGKILL: usize = 9;
+pub const SIGSEGV: usize = 11;
+
+pub fn exit_status(code: usize) -> usize {
+    (code & 0xff) << 8
+}
 
 pub struct TaskManager {
+    // every thread but the idle one
     tasks: RwLock<LinkedList<Arc<Task>>>,
+    // holds the ready ones
+    scheduler: SpinLock<Box<dyn Scheduler>>,
+    // None while idling
+    running: SpinLock<Option<Arc<Task>>>,
+    idle: SpinLock<Option<Arc<Task>>>,
     ids: SpinLock<IdentifierGenerator>,
+    // removed tasks whose page table may still be bound
+    dead: SpinLock<Vec<Arc<Task>>>,
+    zombies: SpinLock<Vec<Zombie>>,
+}
+
+// what is left of an exited process until its parent waits for it
+struct Zombie {
+    id: usize,
+    parent: usize,
+    status: usize,
+}
+
+#[derive(Debug)]
+pub enum WaitError {
+    NoChild,
+}
+
+#[derive(Debug)]
+pub enum JoinError {
+    // not a thread of the process, or already joined
+    NoThread,
+}
+
+#[derive(Debug)]
+pub enum PriorityError {
+    NoTask,
+    // neither the caller's process nor one of its children
+    NotPermitted,
+}
+
+#[derive(Debug)]
+pub enum SchedError {
+    NoTask,
+    // deadline tasks would reserve more than the limit
+    NoBandwidth,
 }
 
 struct IdentifierGenerator {
@@ -23,33 +86,429 @@ struct IdentifierGenerator {
 }
 
 impl TaskManager {
-    pub const fn new() -> Self {
+    pub fn new(scheduler: Box<dyn Scheduler>) -> Self {
         Self {
             tasks: RwLock::new(LinkedList::new()),
+            scheduler: SpinLock::new(scheduler),
+            running: SpinLock::new(None),
+            idle: SpinLock::new(None),
             ids: SpinLock::new(IdentifierGenerator::new(1)),
+            dead: SpinLock::new(Vec::new()),
+            zombies: SpinLock::new(Vec::new()),
         }
     }
 
-    pub fn add_task<R: Readable>(&self, elf_file: R) {
+    pub fn add_task(&self, image: Image, parent: usize) -> Result<usize, TaskError> {
+        let id = self.ids.lock().alloc();
+        let task = Task::new(id, parent, image).inspect_err(|_| self.ids.lock().free(id))?;
+        let arc = Arc::new(task);
+        self.tasks.exclusive_access().push_back(arc.clone());
+        self.scheduler.lock().enqueue(arc);
+        Ok(id)
+    }
+
+    pub fn add_kernel_thread(&self, name: &str, f: KernelThreadFn) -> Result<usize, TaskError> {
+        let id = self.ids.lock().alloc();
+        let process = self.kernel_process();
+        let task = Task::new_kernel(id, name, process, kernel_thread_start, Some(f))
+            .inspect_err(|_| self.ids.lock().free(id))?;
+        let arc = Arc::new(task);
+        self.tasks.exclusive_access().push_back(arc.clone());
+        self.scheduler.lock().enqueue(arc);
+        Ok(id)
+    }
+
+    pub fn fork_current_task(&self, frame: &SyscallFrame) -> Result<usize, TaskError> {
+        let current = self.current_task().ok_or(TaskError::NoTask)?;
         let id = self.ids.lock().alloc();
-        let task = Task::new(id, elf_file);
+        let task = current
+            .fork(id, frame)
+            .inspect_err(|_| self.ids.lock().free(id))?;
         let arc = Arc::new(task);
-        self.tasks.exclusive_access().push_back(arc);
+        self.tasks.exclusive_access().push_back(arc.clone());
+        self.scheduler.lock().enqueue(arc);
+        Ok(id)
     }
 
     pub fn current_task(&self) -> Option<Arc<Task>> {
-        self.tasks.shared_access().front().cloned()
+        self.running.lock().clone()
+    }
+
+    pub fn find_task(&self, id: usize) -> Option<Arc<Task>> {
+        self.tasks
+            .shared_access()
+            .iter()
+            .find(|x| x.id() == id)
+            .cloned()
+    }
+
+    // makes a blocked or sleeping task ready, false if it was neither
+    pub fn wake(&self, task: &Arc<Task>) -> bool {
+        let woken = task.wake();
+        if woken {
+            self.scheduler.lock().enqueue(task.clone());
+        }
+        woken
+    }
+
+    // moves the task to another policy, deadline tasks only if their bandwidth still fits
+    pub fn set_policy(
+        &self,
+        id: usize,
+        policy: SchedPolicy,
+        nice: Option<i8>,
+    ) -> Result<(), SchedError> {
+        let task = self.find_task(id).ok_or(SchedError::NoTask)?;
+        let reserved: u64 = self
+            .tasks
+            .shared_access()
+            .iter()
+            .filter(|x| x.id() != id)
+            .map(|x| x.sched().lock().policy.bandwidth())
+            .sum();
+        if reserved + policy.bandwidth() > DEADLINE_BANDWIDTH_LIMIT {
+            return Err(SchedError::NoBandwidth);
+        }
+
+        let mut scheduler = self.scheduler.lock();
+        let queued = scheduler.dequeue(&task);
+        {
+            let mut sched = task.sched().lock();
+            sched.policy = policy;
+            // a deadline task starts with a new period
+            sched.deadline = 0;
+            sched.budget = 0;
+            sched.throttled = false;
+            if let Some(nice) = nice {
+                sched.nice = nice.clamp(NICE_MIN, NICE_MAX);
+            }
+        }
+        if let Some(task) = queued {
+            scheduler.enqueue(task);
+        }
+        Ok(())
+    }
+
+    // the threads of a process share their nice value, as far as they were not changed one by one
+    pub fn nice(&self, pid: usize) -> Result<i8, PriorityError> {
+        let tasks = self.tasks.shared_access();
+        let task = tasks
+            .iter()
+            .find(|x| x.pid() == pid)
+            .ok_or(PriorityError::NoTask)?;
+        Ok(task.sched().lock().nice)
     }
 
-    pub fn rotate_tasks(&self) -> Option<Arc<Task>> {
-        let mut lock = self.tasks.exclusive_access();
-        let tasks = &mut *lock;
-        let task_option = tasks.pop_back();
-        if let Some(task) = task_option {
-            tasks.push_front(task);
-            tasks.front().cloned()
+    // applies to every thread of process `pid`, which has to be the one of `caller` or one of
+    // its children. out of range values are clamped
+    pub fn set_nice(&self, caller: usize, pid: usize, nice: isize) -> Result<(), PriorityError> {
+        let tasks = self.tasks.shared_access();
+        let mut threads = tasks.iter().filter(|x| x.pid() == pid).peekable();
+        let process = threads.peek().ok_or(PriorityError::NoTask)?.process();
+        if pid != caller && process.parent() != caller {
+            return Err(PriorityError::NotPermitted);
+        }
+        let nice = nice.clamp(NICE_MIN as isize, NICE_MAX as isize) as i8;
+        for task in threads {
+            task.sched().lock().nice = nice;
+        }
+        Ok(())
+    }
+
+    // runs whenever no other task is ready, its process owns the kernel threads
+    pub fn set_idle_task(&self, task: Task) {
+        task.set_state(TaskState::Ready);
+        *self.idle.lock() = Some(Arc::new(task));
+    }
+
+    fn kernel_process(&self) -> Arc<Process> {
+        let idle = self.idle.lock();
+        let idle = idle.as_ref().expect("Kernel threads need the idle task.");
+        idle.process().clone()
+    }
+
+    // a new thread in the process of the running task
+    pub fn create_thread(&self, entry: usize, stack: usize, arg: usize) -> Option<usize> {
+        let current = self.current_task()?;
+        let id = self.ids.lock().alloc();
+        let Ok(task) = current.new_thread(id, entry, stack, arg) else {
+            self.ids.lock().free(id);
+            return None;
+        };
+        let arc = Arc::new(task);
+        self.tasks.exclusive_access().push_back(arc.clone());
+        self.scheduler.lock().enqueue(arc);
+        Some(id)
+    }
+
+    // ends the running task and kills the rest of its process, which exits with `status` once
+    // its last thread is gone
+    pub fn exit_current_process(&self, status: usize) {
+        let Some(task) = self.current_task() else {
+            return;
+        };
+        // the kernel process stays, a kernel thread only ends itself
+        if !task.is_kernel_thread() {
+            self.kill_process(task.pid(), status);
+        }
+        drop(task);
+        self.exit_current_thread(0);
+    }
+
+    // marks process `pid` as exiting with `status` and wakes its threads, each exits at its next
+    // return to user mode or when it is scheduled there. false if there is no such process
+    pub fn kill_process(&self, pid: usize, status: usize) -> bool {
+        let mut found = false;
+        for task in self.tasks.shared_access().iter().filter(|x| x.pid() == pid) {
+            if !found {
+                task.process().set_exiting(status);
+                found = true;
+            }
+            self.wake(task);
+        }
+        found
+    }
+
+    // the process goes with its last thread, exiting with `value` as the code unless it was
+    // killed
+    pub fn exit_current_thread(&self, value: usize) {
+        let mut dead = self.dead.lock();
+        dead.clear();
+        let Some(task) = self.running.lock().take() else {
+            return;
+        };
+        self.unlink(task.id());
+        task.set_state(TaskState::Zombie);
+        let process = task.process().clone();
+        let id = task.id();
+        let kernel = task.is_kernel_thread();
+        dead.push(task);
+
+        if kernel {
+            self.ids.lock().free(id);
+            return;
+        }
+        let last = !self
+            .tasks
+            .shared_access()
+            .iter()
+            .any(|x| Arc::ptr_eq(x.process(), &process));
+        if last {
+            if id != process.pid() {
+                self.ids.lock().free(id);
+            }
+            let status = process.exiting().unwrap_or(exit_status(value));
+            self.bury(&process, status);
+        } else if process.exiting().is_some() {
+            // nobody joins the threads of an exiting process
+            if id != process.pid() {
+                self.ids.lock().free(id);
+            }
         } else {
-            panic!("No more task to schedule.")
+            process.exited_threads().lock().push((id, value));
+            for waiter in process.thread_exit().take_waiters() {
+                self.wake(&waiter);
+            }
+        }
+    }
+
+    fn unlink(&self, id: usize) -> Option<Arc<Task>> {
+        let mut tasks = self.tasks.exclusive_access();
+        let mut cursor = tasks.cursor_front_mut();
+        loop {
+            match cursor.current() {
+                Some(task) if task.id() == id => break cursor.remove_current(),
+                Some(_) => cursor.move_next(),
+                None => break None,
+            }
+        }
+    }
+
+    // kills and wakes the other threads of the process of `current`, false once none are left
+    pub fn kill_other_threads(&self, current: &Task) -> bool {
+        let mut found = false;
+        for task in self
+            .tasks
+            .shared_access()
+            .iter()
+            .filter(|x| x.pid() == current.pid() && x.id() != current.id())
+        {
+            task.kill();
+            self.wake(task);
+            found = true;
+        }
+        found
+    }
+
+    // leaves a zombie for the parent, orphans and their zombies give their pids back at once
+    fn bury(&self, process: &Process, status: usize) {
+        let pid = process.pid();
+        // threads blocked on the process are gone, nobody else waits there
+        process.child_exit().take_waiters();
+        process.thread_exit().take_waiters();
+
+        let mut parent = None;
+        for task in self.tasks.shared_access().iter() {
+            if task.process().parent() == pid {
+                task.process().set_parent(0);
+            }
+            if process.parent() != 0 && task.pid() == process.parent() {
+                parent = Some(task.process().clone());
+            }
+        }
+
+        let mut zombies = self.zombies.lock();
+        let mut ids = self.ids.lock();
+        for (id, _) in process.exited_threads().lock().drain(..) {
+            if id != pid {
+                ids.free(id);
+            }
+        }
+        zombies.retain(|x| {
+            if x.parent == pid {
+                ids.free(x.id);
+            }
+            x.parent != pid
+        });
+        if process.parent() == 0 {
+            ids.free(pid);
+        } else {
+            zombies.push(Zombie {
+                id: pid,
+                parent: process.parent(),
+                status,
+            });
+        }
+        drop(ids);
+        drop(zombies);
+        if let Some(parent) = parent {
+            for waiter in parent.child_exit().take_waiters() {
+                self.wake(&waiter);
+            }
+        }
+    }
+
+    // puts `address_space` in place of the one of the running task's process, whose other
+    // threads have to be gone. the caller has to start the task over, whatever it ran before is
+    // gone
+    pub fn exec_current(&self, address_space: AddressSpace) -> Option<Arc<Task>> {
+        let current = self.current_task()?;
+        let process = current.process().clone();
+        let pid = process.pid();
+        // nobody joins the threads of the old program
+        let mut ids = self.ids.lock();
+        for (id, _) in process.exited_threads().lock().drain(..) {
+            if id != pid {
+                ids.free(id);
+            }
+        }
+        drop(ids);
+
+        current.set_fs_base(0);
+        let old = core::mem::replace(&mut *process.address_space().lock(), address_space);
+        // off the old page table before it is freed
+        unsafe { process.address_space().lock().page_table().bind() };
+        drop(old);
+        Some(current)
+    }
+
+    // reaps an exited child of `parent`, any child if `pid` is None. Ok(None) while the
+    // matching children are all still running
+    pub fn wait(
+        &self,
+        parent: usize,
+        pid: Option<usize>,
+    ) -> Result<Option<(usize, usize)>, WaitError> {
+        let matches = |id: usize| pid.is_none_or(|x| x == id);
+        let mut zombies = self.zombies.lock();
+        if let Some(idx) = zombies
+            .iter()
+            .position(|x| x.parent == parent && matches(x.id))
+        {
+            let zombie = zombies.swap_remove(idx);
+            self.ids.lock().free(zombie.id);
+            return Ok(Some((zombie.id, zombie.status)));
+        }
+        drop(zombies);
+
+        let running = self
+            .tasks
+            .shared_access()
+            .iter()
+            .any(|x| x.process().parent() == parent && matches(x.pid()));
+        if running {
+            Ok(None)
+        } else {
+            Err(WaitError::NoChild)
+        }
+    }
+
+    // takes the exit value of thread `tid` of `process`. Ok(None) while it still runs
+    pub fn join(&self, process: &Process, tid: usize) -> Result<Option<usize>, JoinError> {
+        let mut exited = process.exited_threads().lock();
+        if let Some(idx) = exited.iter().position(|&(id, _)| id == tid) {
+            let (id, value) = exited.swap_remove(idx);
+            // the pid stays taken until the process is reaped
+            if id != process.pid() {
+                self.ids.lock().free(id);
+            }
+            return Ok(Some(value));
+        }
+        drop(exited);
+
+        let running = self
+            .tasks
+            .shared_access()
+            .iter()
+            .any(|x| x.id() == tid && x.pid() == process.pid());
+        if running {
+            Ok(None)
+        } else {
+            Err(JoinError::NoThread)
+        }
+    }
+
+    // a thread of the user process with the most resident pages
+    pub fn largest_task(&self) -> Option<Arc<Task>> {
+        self.tasks
+            .shared_access()
+            .iter()
+            .filter(|x| !x.is_kernel_thread())
+            .max_by_key(|x| x.address_space().lock().resident_pages())
+            .cloned()
+    }
+
+    // the running task goes back to the scheduler unless it blocked, the idle task runs if
+    // nothing is ready
+    pub fn pick_next(&self) -> Option<Arc<Task>> {
+        let mut running = self.running.lock();
+        let mut scheduler = self.scheduler.lock();
+        if let Some(task) = running.take() {
+            if task.state() == TaskState::Running {
+                task.set_state(TaskState::Ready);
+                scheduler.enqueue(task);
+            }
+        }
+
+        if let Some(task) = scheduler.pick_next() {
+            task.set_state(TaskState::Running);
+            *running = Some(task.clone());
+            return Some(task);
+        }
+        self.idle.lock().clone()
+    }
+
+    // charges the timer tick, true if the running task should be switched out
+    pub fn tick(&self) -> bool {
+        let running = self.running.lock();
+        let preempt = self.scheduler.lock().tick(running.as_deref());
+        // the idle task gives way to anything
+        preempt || running.is_none()
+    }
+
+    pub fn yield_current_task(&self) {
+        if let Some(task) = self.running.lock().as_ref() {
+            self.scheduler.lock().yield_task(task);
         }
     }
 }
@@ -77,25 +536,244 @@ impl IdentifierGenerator {
     }
 }
 
-pub static TASK_MANAGER: SpinLockNoIrq<TaskManager> = SpinLockNoIrq::new(TaskManager::new());
+lazy_static! {
+    pub static ref TASK_MANAGER: SpinLockNoIrq<TaskManager> = SpinLockNoIrq::new(TaskManager::new(
+        Box::new(ClassScheduler::new(FairScheduler::DEFAULT_SLICE))
+    ));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomPolicy {
+    // the allocation fails, a faulting task gets killed
+    Fail,
+    Panic,
+    // kill the task with the most resident pages and retry
+    KillLargest,
+}
+
+// the default, kernel_boot picks another one if the build asks for it
+static OOM_POLICY: SpinLock<OomPolicy> = SpinLock::new(OomPolicy::KillLargest);
+
+pub fn set_oom_policy(policy: OomPolicy) {
+    *OOM_POLICY.lock() = policy;
+}
+
+extern "sysv64" fn idle() -> ! {
+    loop {
+        wait_for_interrupt();
+    }
+}
+
+// where a killed thread goes instead of back to user mode
+extern "sysv64" fn exit_killed_thread() -> ! {
+    exit_current_thread(0)
+}
+
+// first code of every kernel thread, the task exits once its function returns
+extern "sysv64" fn kernel_thread_start() -> ! {
+    // nothing of the task may be held while it runs, it might exit from within
+    let f = TASK_MANAGER.lock().current_task().and_then(|task| {
+        trace!(
+            "Kernel thread {} starts as task {}.",
+            task.name().unwrap_or_default(),
+            task.id()
+        );
+        task.take_kernel_fn()
+    });
+    if let Some(f) = f {
+        f();
+    }
+    exit_current_thread(0)
+}
 
 pub fn init_first_process_and_jump_to() -> ! {
     trace!("Preparing for init task...");
     let task = {
         let lock = TASK_MANAGER.lock();
-        lock.add_task(MemoryReader::new(INIT_PROGRAM.as_ptr(), INIT_PROGRAM.len()));
-        lock.current_task()
+        let process = Process::new_kernel().expect("Cannot create kernel process.");
+        let idle = Task::new_kernel(0, "idle", Arc::new(process), idle, None)
+            .expect("Cannot create idle task.");
+        lock.set_idle_task(idle);
+        let args = ProgramArgs {
+            argv: vec![INIT_PATH.to_vec()],
+            envp: Vec::new(),
+        };
+        let image = load_image(
+            MemoryReader::new(INIT_PROGRAM.as_ptr(), INIT_PROGRAM.len()),
+            &args,
+        )
+        .expect("Cannot load init program.");
+        lock.add_task(image, 0).expect("Cannot create init task.");
+        lock.pick_next()
     };
     free_initial_page_table();
     trace!("Init starts.");
     unsafe { task.unwrap().jump_to() }
 }
 
-pub fn schedule_next_task() -> ! {
-    let task = TASK_MANAGER.lock().rotate_tasks();
-    if let Some(task) = task {
-        unsafe { task.jump_to() }
-    } else {
-        panic!("No task to run.");
+pub fn exit_current_process(status: usize) -> ! {
+    TASK_MANAGER.lock().exit_current_process(status);
+    schedule_next_task()
+}
+
+// runs `image` in place of the program of the running task, which keeps its ids. the other
+// threads of its process are killed first, and waited for as they might still use the old one
+pub fn exec_current_task(image: Image) -> ! {
+    let current = TASK_MANAGER
+        .lock()
+        .current_task()
+        .expect("No task to exec.");
+    // checked again whenever one exits, threads they created meanwhile get killed as well
+    current
+        .process()
+        .thread_exit()
+        .wait_until(|| !TASK_MANAGER.lock().kill_other_threads(&current));
+    if current.is_killed() {
+        // by another thread exiting the process or exec'ing itself, the new program never runs
+        drop(image);
+        drop(current);
+        exit_current_thread(0);
+    }
+    drop(current);
+
+    let current = TASK_MANAGER
+        .lock()
+        .exec_current(image.address_space)
+        .expect("No task to exec.");
+    // the task list keeps it alive while it runs, the reference on this stack would never be
+    // dropped
+    let task = Arc::as_ptr(&current);
+    drop(current);
+    unsafe { Task::start_over(task, image.entry, image.stack) }
+}
+
+pub fn exit_current_thread(value: usize) -> ! {
+    TASK_MANAGER.lock().exit_current_thread(value);
+    schedule_next_task()
+}
+
+pub fn kill_current_process(signal: usize) -> ! {
+    exit_current_process(signal)
+}
+
+// ends the running thread if it was killed, for when it is about to return to user mode
+pub fn exit_if_killed() {
+    if current_is_killed() {
+        exit_current_thread(0);
+    }
+}
+
+// returns false if the fault is not resolvable for the current task. true leaves the access to be
+// retried, after an oom kill it faults again until the victim exited and its frames are free
+pub fn handle_page_fault(addr: VirtAddress, fault: FaultFlags) -> bool {
+    let Some(task) = TASK_MANAGER.lock().current_task() else {
+        return false;
+    };
+    let result = task.address_space().lock().handle_fault(addr, fault);
+    drop(task);
+    match result {
+        Ok(()) => true,
+        Err(FaultError::OutOfMemory) => handle_out_of_memory(),
+        Err(_) => false,
+    }
+}
+
+// runs `alloc` again for as long as `is_oom` says it ran out of memory and the oom policy killed a
+// process for it. made from syscalls, so the victim gets the cpu to exit before the next try
+pub fn retry_out_of_memory<T, E>(
+    mut alloc: impl FnMut() -> Result<T, E>,
+    is_oom: impl Fn(&E) -> bool,
+) -> Result<T, E> {
+    loop {
+        let result = alloc();
+        match &result {
+            // a killed caller gives up, it exits on its way out
+            Err(error) if is_oom(error) && !current_is_killed() && handle_out_of_memory() => {
+                yield_current_task();
+            }
+            _ => return result,
+        }
+    }
+}
+
+fn current_is_killed() -> bool {
+    TASK_MANAGER
+        .lock()
+        .current_task()
+        .is_some_and(|x| x.is_killed())
+}
+
+// applies the oom policy, returns true if a process was killed and the allocation can be retried
+fn handle_out_of_memory() -> bool {
+    let policy = *OOM_POLICY.lock();
+    match policy {
+        OomPolicy::Fail => false,
+        OomPolicy::Panic => panic!("Out of memory."),
+        OomPolicy::KillLargest => {
+            let (victim, current) = {
+                let lock = TASK_MANAGER.lock();
+                (lock.largest_task(), lock.current_task())
+            };
+            let Some(victim) = victim else {
+                return false;
+            };
+
+            let pid = victim.pid();
+            drop(victim);
+            trace!("Out of memory, killing process {}.", pid);
+            if current.is_some_and(|x| x.pid() == pid) {
+                kill_current_process(SIGKILL);
+            }
+            // it is only freed once it gets to run, not from under whatever it is doing
+            TASK_MANAGER.lock().kill_process(pid, SIGKILL)
+        }
     }
 }
+
+pub extern "sysv64" fn schedule_next_task() -> ! {
+    let task = TASK_MANAGER.lock().pick_next();
+    let Some(task) = task else {
+        panic!("No idle task.");
+    };
+    resume(&task)
+}
+
+// entered from the timer interrupt, the running task goes on unless the scheduler says otherwise
+pub extern "sysv64" fn preempt_current_task() -> ! {
+    let task = {
+        let lock = TASK_MANAGER.lock();
+        if lock.tick() {
+            lock.pick_next()
+        } else {
+            lock.current_task()
+        }
+    };
+    let Some(task) = task else {
+        panic!("No idle task.");
+    };
+    resume(&task)
+}
+
+// a killed thread is not let back into user mode, it exits instead
+fn resume(task: &Task) -> ! {
+    if task.registers.in_user_mode() && task.is_killed() {
+        unsafe { task.start_in_kernel(exit_killed_thread) }
+    }
+    unsafe { task.jump_to() }
+}
+
+// switches away from the current task, returning once it is scheduled again. a blocked task
+// only is after being woken
+pub fn block_current() {
+    unsafe { switch_out(schedule_next_task) }
+}
+
+pub fn yield_current_task() {
+    TASK_MANAGER.lock().yield_current_task();
+    block_current();
+}
+
+// wakes a task blocked or sleeping outside a wait queue
+pub fn wake_task(task: &Arc<Task>) -> bool {
+    TASK_MANAGER.lock().wake(task)
+}
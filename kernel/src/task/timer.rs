@@ -0,0 +1,119 @@
+#![allow(dead_code)]
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    arch::{NANOS_PER_TICK, disable_irq, restore_irq},
+    sync::SpinLockNoIrq,
+};
+
+use super::{
+    task::TaskState,
+    task_mgr::{TASK_MANAGER, block_current, wake_task},
+};
+
+// timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+type Callback = Box<dyn Fn() + Send>;
+
+struct Timer {
+    callback: Callback,
+    // fired timers stay until cancelled, so the interrupt never frees anything
+    fired: bool,
+}
+
+// timers by deadline, ties broken by when they were added
+struct Timers {
+    pending: BTreeMap<(u64, u64), Timer>,
+    next_id: u64,
+}
+
+static TIMERS: SpinLockNoIrq<Timers> = SpinLockNoIrq::new(Timers {
+    pending: BTreeMap::new(),
+    next_id: 0,
+});
+
+// identifies a pending timer for cancelling it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerHandle {
+    deadline: u64,
+    id: u64,
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// ticks needed to cover `nanos`, rounded up
+pub fn nanos_to_ticks(nanos: u64) -> u64 {
+    nanos.div_ceil(NANOS_PER_TICK)
+}
+
+// runs `callback` from the timer interrupt once `deadline` in ticks has passed, with interrupts
+// disabled. it must neither block nor touch timers. every timer has to be cancelled in the end,
+// fired or not, which frees it
+pub fn add_timer(deadline: u64, callback: impl Fn() + Send + 'static) -> TimerHandle {
+    let mut timers = TIMERS.lock();
+    let id = timers.next_id;
+    timers.next_id += 1;
+    let timer = Timer {
+        callback: Box::new(callback),
+        fired: false,
+    };
+    timers.pending.insert((deadline, id), timer);
+    TimerHandle { deadline, id }
+}
+
+// false if the timer already fired
+pub fn cancel_timer(handle: TimerHandle) -> bool {
+    TIMERS
+        .lock()
+        .pending
+        .remove(&(handle.deadline, handle.id))
+        .is_some_and(|x| !x.fired)
+}
+
+// called on every timer interrupt, it must not reach the allocator
+pub fn timer_tick() {
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    let mut timers = TIMERS.lock();
+    for timer in timers.pending.range_mut(..=(now, u64::MAX)).map(|(_, x)| x) {
+        if !timer.fired {
+            timer.fired = true;
+            (timer.callback)();
+        }
+    }
+}
+
+// parks the current task until `deadline` in ticks, or until it is killed
+pub fn sleep_until(deadline: u64) {
+    while ticks() < deadline {
+        let enabled = unsafe { disable_irq() };
+        let current = TASK_MANAGER.lock().current_task();
+        let Some(current) = current.filter(|x| !x.is_killed()) else {
+            unsafe { restore_irq(enabled) };
+            return;
+        };
+        current.set_state(TaskState::Sleeping);
+        // the task list keeps the task alive while it sleeps, so the interrupt never drops the
+        // last reference
+        let task = Arc::downgrade(&current);
+        let handle = add_timer(deadline, move || {
+            if let Some(task) = task.upgrade() {
+                wake_task(&task);
+            }
+        });
+        drop(current);
+        block_current();
+        // it is still pending if a kill woke the task
+        cancel_timer(handle);
+        unsafe { restore_irq(enabled) };
+    }
+}
+
+// sleeps for at least `nanos`, the tick in progress does not count
+pub fn sleep(nanos: u64) {
+    sleep_until(ticks() + nanos_to_ticks(nanos) + 1);
+}
@@ -0,0 +1,86 @@
+#![allow(dead_code)]
+
+use alloc::{collections::VecDeque, sync::Arc};
+
+use crate::{
+    arch::{disable_irq, restore_irq},
+    sync::SpinLockNoIrq,
+};
+
+use super::{
+    task::{Task, TaskState},
+    task_mgr::{TASK_MANAGER, block_current, wake_task},
+};
+
+// tasks blocked until something happens, woken from syscalls or interrupt handlers
+pub struct WaitQueue {
+    waiters: SpinLockNoIrq<VecDeque<Arc<Task>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: SpinLockNoIrq::new(VecDeque::new()),
+        }
+    }
+
+    // blocks the current task until `done` returns true, it is checked again after every wakeup.
+    // the task is queued before checking, so a wakeup in between is not lost. it also returns
+    // once the task is killed, to let it exit on its way back to user mode
+    pub fn wait_until(&self, mut done: impl FnMut() -> bool) {
+        loop {
+            let enabled = unsafe { disable_irq() };
+            let Some(current) = TASK_MANAGER.lock().current_task() else {
+                unsafe { restore_irq(enabled) };
+                return;
+            };
+            if current.is_killed() {
+                // a kill wakes the task without taking it off the queue
+                self.remove(&current);
+                unsafe { restore_irq(enabled) };
+                return;
+            }
+            current.set_state(TaskState::Blocked);
+            self.waiters.lock().push_back(current.clone());
+
+            if done() {
+                self.remove(&current);
+                current.set_state(TaskState::Running);
+                unsafe { restore_irq(enabled) };
+                return;
+            }
+            // the task might never run again, nothing of it may stay on its stack
+            drop(current);
+            block_current();
+            unsafe { restore_irq(enabled) };
+        }
+    }
+
+    // wakes the longest waiting task, false if there was none
+    pub fn wake_one(&self) -> bool {
+        loop {
+            let Some(task) = self.waiters.lock().pop_front() else {
+                return false;
+            };
+            // exited tasks are just dropped
+            if wake_task(&task) {
+                return true;
+            }
+        }
+    }
+
+    pub fn wake_all(&self) {
+        for task in self.take_waiters() {
+            wake_task(&task);
+        }
+    }
+
+    // empties the queue without waking anyone, for callers that hold the task manager
+    pub fn take_waiters(&self) -> VecDeque<Arc<Task>> {
+        core::mem::take(&mut *self.waiters.lock())
+    }
+
+    fn remove(&self, task: &Arc<Task>) {
+        self.waiters.lock().retain(|x| !Arc::ptr_eq(x, task));
+    }
+}
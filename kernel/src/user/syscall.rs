@@ -1,51 +1,681 @@
+use alloc::{vec, vec::Vec};
+
 use crate::{
-    INIT_PROGRAM,
-    arch::x86_64::serial::COM1,
-    mm::definitions::KERNEL_REGION_BEGIN,
-    task::{MemoryReader, TASK_MANAGER},
+    arch::{
+        SyscallFrame,
+        x86_64::{serial::COM1, task::set_thread_pointer},
+    },
+    mm::{
+        address_space::{AreaError, Placement},
+        definitions::{FRAME_SIZE, PageFlags, PageRegion, VirtAddress},
+        layout::{MMAP_BEGIN, USER_REGION_END},
+        user_access::{UserAccessError, UserPtr, UserSlice, strncpy_from_user},
+    },
+    task::{
+        Image, JoinError, MemoryReader, PriorityError, ProgramArgs, SchedError, TASK_MANAGER,
+        TaskError, WaitError, exec_current_task, exit_current_process, exit_current_thread,
+        exit_if_killed, exit_status, find_program, load_image, retry_out_of_memory,
+        sched::{
+            DeadlineParams, NICE_MAX, NICE_MIN, RT_PRIORITY_MAX, RT_PRIORITY_MIN, SchedPolicy,
+        },
+        sleep,
+        timer::nanos_to_ticks,
+        yield_current_task,
+    },
 };
 
-pub fn handle_syscall(num: usize, parameters: &[usize]) -> usize {
+const PROT_WRITE: usize = 0x2;
+const PROT_EXEC: usize = 0x4;
+
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+
+const WNOHANG: usize = 1;
+
+const PRIO_PROCESS: usize = 0;
+
+const ARCH_SET_FS: usize = 0x1002;
+const ARCH_GET_FS: usize = 0x1003;
+
+const SCHED_NORMAL: u32 = 0;
+const SCHED_FIFO: u32 = 1;
+const SCHED_RR: u32 = 2;
+const SCHED_DEADLINE: u32 = 6;
+
+const EPERM: usize = 1;
+const ENOENT: usize = 2;
+const ESRCH: usize = 3;
+const EINTR: usize = 4;
+const E2BIG: usize = 7;
+const ENOEXEC: usize = 8;
+const EBADF: usize = 9;
+const ECHILD: usize = 10;
+const ENOMEM: usize = 12;
+const EACCES: usize = 13;
+const EFAULT: usize = 14;
+const EBUSY: usize = 16;
+const EINVAL: usize = 22;
+const EDEADLK: usize = 35;
+const ENOSYS: usize = 38;
+const ENAMETOOLONG: usize = 36;
+
+const PATH_MAX: usize = 256;
+// longest single argument or environment string, and what they may take up together
+const MAX_ARG_STRLEN: usize = FRAME_SIZE;
+const ARG_MAX: usize = 32 * FRAME_SIZE;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Timespec {
+    tv_sec: u64,
+    tv_nsec: u64,
+}
+
+// as sched_setattr takes it on linux
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct SchedAttr {
+    size: u32,
+    sched_policy: u32,
+    sched_flags: u64,
+    sched_nice: i32,
+    sched_priority: u32,
+    sched_runtime: u64,
+    sched_deadline: u64,
+    sched_period: u64,
+}
+
+// a killed thread exits here instead of returning to user mode
+pub fn handle_syscall(num: usize, parameters: &[usize], frame: &SyscallFrame) -> usize {
+    let result = dispatch_syscall(num, parameters, frame);
+    exit_if_killed();
+    result
+}
+
+// user pointers are wrapped here, handlers only touch user memory through them
+fn dispatch_syscall(num: usize, parameters: &[usize], frame: &SyscallFrame) -> usize {
     if num == 1 {
-        syscall_write(parameters)
+        syscall_write(parameters[0], UserSlice::new(parameters[1], parameters[2]))
     } else if num == 3 {
-        syscall_getpid(parameters)
+        syscall_getpid()
     } else if num == 4 {
-        syscall_spawn(parameters)
+        syscall_spawn(
+            UserPtr::new(parameters[0]),
+            UserPtr::new(parameters[1]),
+            UserPtr::new(parameters[2]),
+        )
+    } else if num == 5 {
+        syscall_fork(frame)
+    } else if num == 6 {
+        syscall_mmap(
+            UserPtr::new(parameters[0]),
+            parameters[1],
+            parameters[2],
+            parameters[3],
+        )
+    } else if num == 7 {
+        syscall_munmap(UserSlice::new(parameters[0], parameters[1]))
+    } else if num == 8 {
+        syscall_mprotect(UserSlice::new(parameters[0], parameters[1]), parameters[2])
+    } else if num == 9 {
+        syscall_brk(UserPtr::new(parameters[0]))
+    } else if num == 10 {
+        syscall_exit(parameters[0])
+    } else if num == 11 {
+        syscall_waitpid(parameters[0], UserPtr::new(parameters[1]), parameters[2])
+    } else if num == 12 {
+        syscall_sleep(parameters[0])
+    } else if num == 13 {
+        syscall_nanosleep(UserPtr::new(parameters[0]))
+    } else if num == 14 {
+        syscall_sched_yield()
+    } else if num == 15 {
+        syscall_setpriority(parameters[0], parameters[1], parameters[2])
+    } else if num == 16 {
+        syscall_getpriority(parameters[0], parameters[1])
+    } else if num == 17 {
+        syscall_sched_setattr(parameters[0], UserPtr::new(parameters[1]), parameters[2])
+    } else if num == 18 {
+        syscall_thread_create(parameters[0], parameters[1], parameters[2])
+    } else if num == 19 {
+        syscall_thread_exit(parameters[0])
+    } else if num == 20 {
+        syscall_thread_join(parameters[0], UserPtr::new(parameters[1]))
+    } else if num == 21 {
+        syscall_arch_prctl(parameters[0], parameters[1])
+    } else if num == 22 {
+        syscall_execve(
+            UserPtr::new(parameters[0]),
+            UserPtr::new(parameters[1]),
+            UserPtr::new(parameters[2]),
+        )
     } else {
-        !0
+        error(ENOSYS)
     }
 }
 
-fn syscall_write(parameters: &[usize]) -> usize {
-    let fp = parameters[0];
-    let data = parameters[1];
-    let len = parameters[2];
-    if data + len < KERNEL_REGION_BEGIN && fp == 1 {
-        unsafe {
-            let bs = core::slice::from_raw_parts(data as *const u8, len);
-            for b in bs {
-                #[allow(static_mut_refs)]
+// returns the number of bytes written
+fn syscall_write(fp: usize, data: UserSlice) -> usize {
+    if fp != 1 {
+        return error(EBADF);
+    }
+
+    let written = data.for_each_chunk::<64>(|chunk| {
+        for b in chunk {
+            #[allow(static_mut_refs)]
+            unsafe {
                 COM1.write_byte(*b);
             }
-            0
         }
-    } else {
-        1
+    });
+    match written {
+        Ok(()) => data.len(),
+        Err(_) => error(EFAULT),
     }
 }
 
-fn syscall_getpid(_parameters: &[usize]) -> usize {
+fn syscall_getpid() -> usize {
     TASK_MANAGER
         .lock()
         .current_task()
-        .map(|x| x.id())
+        .map(|x| x.pid())
         .unwrap_or(0)
 }
 
-fn syscall_spawn(_parameters: &[usize]) -> usize {
-    TASK_MANAGER
+// errors are returned negated
+fn error(errno: usize) -> usize {
+    errno.wrapping_neg()
+}
+
+// starts program `path` as a child, returning its pid. `argv` and `envp` are null-terminated
+// arrays of strings, a null array is taken as empty
+fn syscall_spawn(path: UserPtr<u8>, argv: UserPtr<usize>, envp: UserPtr<usize>) -> usize {
+    let (program, args) = match read_program(path, argv, envp) {
+        Ok(x) => x,
+        Err(errno) => return errno,
+    };
+    let parent = TASK_MANAGER
         .lock()
-        .add_task(MemoryReader::new(INIT_PROGRAM.as_ptr(), INIT_PROGRAM.len()));
+        .current_task()
+        .map(|x| x.pid())
+        .unwrap_or(0);
+    // a failed add_task drops the image, so every try loads it again
+    let result = retry_out_of_memory(
+        || {
+            let image = load_program(program, &args)?;
+            TASK_MANAGER.lock().add_task(image, parent)
+        },
+        |x| matches!(x, TaskError::OutOfMemory),
+    );
+    match result {
+        Ok(pid) => pid,
+        Err(error) => task_error(error),
+    }
+}
+
+// replaces the program of the calling process, only returns on failure. its other threads are
+// gone once it succeeds
+fn syscall_execve(path: UserPtr<u8>, argv: UserPtr<usize>, envp: UserPtr<usize>) -> usize {
+    let (program, args) = match read_program(path, argv, envp) {
+        Ok(x) => x,
+        Err(errno) => return errno,
+    };
+    let result = retry_out_of_memory(
+        || load_program(program, &args),
+        |x| matches!(x, TaskError::OutOfMemory),
+    );
+    match result {
+        Ok(image) => exec_current_task(image),
+        Err(error) => task_error(error),
+    }
+}
+
+// copies in what spawn and execve are given, errors are negated errnos
+fn read_program(
+    path: UserPtr<u8>,
+    argv: UserPtr<usize>,
+    envp: UserPtr<usize>,
+) -> Result<(&'static [u8], ProgramArgs), usize> {
+    let mut name = [0u8; PATH_MAX];
+    let len = strncpy_from_user(&mut name, path).map_err(|x| match x {
+        UserAccessError::Fault => error(EFAULT),
+        UserAccessError::TooLong => error(ENAMETOOLONG),
+    })?;
+    let program = find_program(&name[..len]).ok_or(error(ENOENT))?;
+
+    let mut buf = vec![0u8; MAX_ARG_STRLEN];
+    let mut total = 0;
+    let args = ProgramArgs {
+        argv: read_strings(argv, &mut buf, &mut total)?,
+        envp: read_strings(envp, &mut buf, &mut total)?,
+    };
+    Ok((program, args))
+}
+
+fn load_program(program: &[u8], args: &ProgramArgs) -> Result<Image, TaskError> {
+    load_image(MemoryReader::new(program.as_ptr(), program.len()), args)
+}
+
+// the strings of a null-terminated array, `total` counting the bytes they take on the stack
+fn read_strings(
+    array: UserPtr<usize>,
+    buf: &mut [u8],
+    total: &mut usize,
+) -> Result<Vec<Vec<u8>>, usize> {
+    let mut result = Vec::new();
+    if array.is_null() {
+        return Ok(result);
+    }
+    for idx in 0.. {
+        let ptr = array.add(idx).read().map_err(|_| error(EFAULT))?;
+        if ptr == 0 {
+            break;
+        }
+        let len = strncpy_from_user(buf, UserPtr::new(ptr)).map_err(|x| match x {
+            UserAccessError::Fault => error(EFAULT),
+            UserAccessError::TooLong => error(E2BIG),
+        })?;
+        // the pointer to it counts as well
+        *total += len + 1 + size_of::<usize>();
+        if *total > ARG_MAX {
+            return Err(error(E2BIG));
+        }
+        result.push(buf[..len].to_vec());
+    }
+    Ok(result)
+}
+
+fn task_error(error: TaskError) -> usize {
+    match error {
+        TaskError::OutOfMemory => self::error(ENOMEM),
+        TaskError::InvalidProgram => self::error(ENOEXEC),
+        TaskError::NoTask => self::error(ESRCH),
+    }
+}
+
+// unmapped ranges count as out of memory, as on linux
+fn area_error(error: AreaError) -> usize {
+    match error {
+        AreaError::OutOfMemory | AreaError::NoSpace | AreaError::NotMapped => self::error(ENOMEM),
+        AreaError::Overlapping => self::error(EINVAL),
+    }
+}
+
+fn syscall_fork(frame: &SyscallFrame) -> usize {
+    let result = retry_out_of_memory(
+        || TASK_MANAGER.lock().fork_current_task(frame),
+        |x| matches!(x, TaskError::OutOfMemory),
+    );
+    match result {
+        Ok(pid) => pid,
+        Err(error) => task_error(error),
+    }
+}
+
+fn prot_to_flags(prot: usize) -> PageFlags {
+    // PROT_NONE leaves the pages inaccessible from user mode
+    let mut flags = if prot == 0 {
+        PageFlags::empty()
+    } else {
+        PageFlags::Usermode
+    };
+    if prot & PROT_WRITE != 0 {
+        flags |= PageFlags::Writable;
+    }
+    if prot & PROT_EXEC != 0 {
+        flags |= PageFlags::Executable;
+    }
+    flags
+}
+
+// page-aligned, non-empty and inside the user region
+fn user_region(slice: UserSlice) -> Option<PageRegion> {
+    let (addr, len) = (slice.addr(), slice.len());
+    let end = addr
+        .checked_add(len)?
+        .checked_next_multiple_of(FRAME_SIZE)?;
+    if addr % FRAME_SIZE != 0 || len == 0 || addr < MMAP_BEGIN || end > USER_REGION_END {
+        None
+    } else {
+        Some(PageRegion::new(
+            VirtAddress::new(addr).get_page(),
+            (end - addr) / FRAME_SIZE,
+        ))
+    }
+}
+
+fn syscall_mmap(addr: UserPtr<u8>, len: usize, prot: usize, flags: usize) -> usize {
+    if flags & MAP_ANONYMOUS == 0 || len == 0 {
+        return error(EINVAL);
+    }
+
+    let num = len.div_ceil(FRAME_SIZE);
+    let range = UserSlice::new(addr.addr(), len);
+    let placement = if flags & MAP_FIXED != 0 {
+        match user_region(range) {
+            Some(region) => Placement::Fixed(region.start()),
+            None => return error(EINVAL),
+        }
+    } else if let Some(region) = user_region(range) {
+        Placement::Hint(region.start())
+    } else {
+        Placement::Anywhere
+    };
+
+    let Some(task) = TASK_MANAGER.lock().current_task() else {
+        return error(ESRCH);
+    };
+    let result = retry_out_of_memory(
+        || {
+            task.address_space()
+                .lock()
+                .map_anonymous(placement, num, prot_to_flags(prot))
+        },
+        |x| matches!(x, AreaError::OutOfMemory),
+    );
+    match result {
+        Ok(page) => {
+            let addr: VirtAddress = page.into();
+            addr.as_usize()
+        }
+        Err(error) => area_error(error),
+    }
+}
+
+fn syscall_munmap(range: UserSlice) -> usize {
+    let Some(region) = user_region(range) else {
+        return error(EINVAL);
+    };
+
+    if let Some(task) = TASK_MANAGER.lock().current_task() {
+        task.address_space().lock().unmap(&region);
+        0
+    } else {
+        error(ESRCH)
+    }
+}
+
+fn syscall_mprotect(range: UserSlice, prot: usize) -> usize {
+    let Some(region) = user_region(range) else {
+        return error(EINVAL);
+    };
+
+    let Some(task) = TASK_MANAGER.lock().current_task() else {
+        return error(ESRCH);
+    };
+    let result = task
+        .address_space()
+        .lock()
+        .protect(&region, prot_to_flags(prot));
+    match result {
+        Ok(()) => 0,
+        Err(error) => area_error(error),
+    }
+}
+
+// returns the new break, a null `addr` only queries it
+fn syscall_brk(addr: UserPtr<u8>) -> usize {
+    let Some(task) = TASK_MANAGER.lock().current_task() else {
+        return error(ESRCH);
+    };
+
+    if addr.is_null() {
+        return task.address_space().lock().get_break();
+    }
+    let result = retry_out_of_memory(
+        || task.address_space().lock().set_break(addr.addr()),
+        |x| matches!(x, AreaError::OutOfMemory),
+    );
+    match result {
+        Ok(addr) => addr,
+        Err(error) => area_error(error),
+    }
+}
+
+fn syscall_exit(code: usize) -> usize {
+    exit_current_process(exit_status(code))
+}
+
+// `pid` of -1 waits for any child, the wait status goes to `status` unless it is null
+fn syscall_waitpid(pid: usize, status: UserPtr<u32>, options: usize) -> usize {
+    let pid = if pid as isize == -1 { None } else { Some(pid) };
+    let Some(current) = TASK_MANAGER.lock().current_task() else {
+        return error(ESRCH);
+    };
+    let process = current.process().clone();
+    drop(current);
+    let id = process.pid();
+
+    let mut result = Ok(None);
+    if options & WNOHANG != 0 {
+        result = TASK_MANAGER.lock().wait(id, pid);
+    } else {
+        // woken by every exiting child
+        process.child_exit().wait_until(|| {
+            result = TASK_MANAGER.lock().wait(id, pid);
+            !matches!(result, Ok(None))
+        });
+    }
+    match result {
+        Ok(Some((id, wait_status))) => {
+            if !status.is_null() && status.write(&(wait_status as u32)).is_err() {
+                return error(EFAULT);
+            }
+            id
+        }
+        Ok(None) if options & WNOHANG != 0 => 0,
+        // killed while waiting, it exits on its way out
+        Ok(None) => error(EINTR),
+        Err(WaitError::NoChild) => error(ECHILD),
+    }
+}
+
+// returns the seconds left, always 0 as nothing cuts a sleep short
+fn syscall_sleep(seconds: usize) -> usize {
+    sleep((seconds as u64).saturating_mul(NANOS_PER_SECOND));
+    0
+}
+
+// nothing cuts a sleep short, so the remaining time is never reported
+fn syscall_nanosleep(duration: UserPtr<Timespec>) -> usize {
+    let Ok(duration) = duration.read() else {
+        return error(EFAULT);
+    };
+    if duration.tv_nsec >= NANOS_PER_SECOND {
+        return error(EINVAL);
+    }
+    sleep(
+        duration
+            .tv_sec
+            .saturating_mul(NANOS_PER_SECOND)
+            .saturating_add(duration.tv_nsec),
+    );
+    0
+}
+
+fn syscall_sched_yield() -> usize {
+    yield_current_task();
     0
 }
+
+// the pids of the calling process and of the target, `who` of 0 being the caller
+fn priority_target(which: usize, who: usize) -> Result<(usize, usize), usize> {
+    if which != PRIO_PROCESS {
+        return Err(error(EINVAL));
+    }
+    let caller = TASK_MANAGER
+        .lock()
+        .current_task()
+        .map(|x| x.pid())
+        .ok_or(error(ESRCH))?;
+    match who {
+        0 => Ok((caller, caller)),
+        x => Ok((caller, x)),
+    }
+}
+
+fn priority_error(error: PriorityError) -> usize {
+    match error {
+        PriorityError::NoTask => self::error(ESRCH),
+        PriorityError::NotPermitted => self::error(EPERM),
+    }
+}
+
+// sets the nice value of every thread of the process. nobody is privileged to go below 0, and
+// only the process itself and its parent may change it
+fn syscall_setpriority(which: usize, who: usize, nice: usize) -> usize {
+    let (caller, pid) = match priority_target(which, who) {
+        Ok(x) => x,
+        Err(errno) => return errno,
+    };
+    if (nice as isize) < 0 {
+        return error(EACCES);
+    }
+    match TASK_MANAGER.lock().set_nice(caller, pid, nice as isize) {
+        Ok(()) => 0,
+        Err(x) => priority_error(x),
+    }
+}
+
+// returns 20 - nice, from 1 to 40, so it cannot be taken for an error
+fn syscall_getpriority(which: usize, who: usize) -> usize {
+    let (_, pid) = match priority_target(which, who) {
+        Ok(x) => x,
+        Err(errno) => return errno,
+    };
+    match TASK_MANAGER.lock().nice(pid) {
+        Ok(nice) => (20 - nice as isize) as usize,
+        Err(x) => priority_error(x),
+    }
+}
+
+// deadline parameters are in nanoseconds, rounded up to whole ticks. a period of 0 is the
+// deadline
+fn sched_policy(attr: &SchedAttr) -> Option<SchedPolicy> {
+    let priority = attr.sched_priority;
+    let fixed = (RT_PRIORITY_MIN as u32..=RT_PRIORITY_MAX as u32).contains(&priority);
+    match attr.sched_policy {
+        SCHED_NORMAL if priority == 0 => Some(SchedPolicy::Normal),
+        SCHED_FIFO if fixed => Some(SchedPolicy::Fifo(priority as u8)),
+        SCHED_RR if fixed => Some(SchedPolicy::RoundRobin(priority as u8)),
+        SCHED_DEADLINE if priority == 0 => {
+            let period = match attr.sched_period {
+                0 => attr.sched_deadline,
+                x => x,
+            };
+            let params = DeadlineParams {
+                runtime: nanos_to_ticks(attr.sched_runtime),
+                deadline: nanos_to_ticks(attr.sched_deadline),
+                period: nanos_to_ticks(period),
+            };
+            let valid = 0 < params.runtime
+                && params.runtime <= params.deadline
+                && params.deadline <= params.period;
+            valid.then_some(SchedPolicy::Deadline(params))
+        }
+        _ => None,
+    }
+}
+
+// `pid` of 0 is the calling task, the nice value only counts for SCHED_NORMAL and may not go
+// below 0, as for setpriority. fixed priorities are not privileged, the realtime class caps what
+// they run instead
+fn syscall_sched_setattr(pid: usize, attr: UserPtr<SchedAttr>, flags: usize) -> usize {
+    if flags != 0 {
+        return error(EINVAL);
+    }
+    let Ok(attr) = attr.read() else {
+        return error(EFAULT);
+    };
+    let Some(policy) = sched_policy(&attr) else {
+        return error(EINVAL);
+    };
+    if policy == SchedPolicy::Normal && attr.sched_nice < 0 {
+        return error(EACCES);
+    }
+    let id = match pid {
+        0 => match TASK_MANAGER.lock().current_task() {
+            Some(task) => task.id(),
+            None => return error(ESRCH),
+        },
+        x => x,
+    };
+    let nice = (policy == SchedPolicy::Normal)
+        .then(|| attr.sched_nice.clamp(NICE_MIN as i32, NICE_MAX as i32) as i8);
+    match TASK_MANAGER.lock().set_policy(id, policy, nice) {
+        Ok(()) => 0,
+        Err(SchedError::NoTask) => error(ESRCH),
+        Err(SchedError::NoBandwidth) => error(EBUSY),
+    }
+}
+
+// runs `entry(arg)` on `stack` in a new thread of the calling process, returning its id. the
+// function may not return, it has to end with thread_exit
+fn syscall_thread_create(entry: usize, stack: usize, arg: usize) -> usize {
+    if entry >= USER_REGION_END || stack > USER_REGION_END {
+        return error(EFAULT);
+    }
+    match TASK_MANAGER.lock().create_thread(entry, stack, arg) {
+        Some(id) => id,
+        None => error(ENOMEM),
+    }
+}
+
+// the process exits along with its last thread, `value` being its exit code then
+fn syscall_thread_exit(value: usize) -> usize {
+    exit_current_thread(value)
+}
+
+// waits for thread `tid` of the calling process, its exit value goes to `value` unless it is
+// null. a thread can be joined once
+fn syscall_thread_join(tid: usize, value: UserPtr<usize>) -> usize {
+    let Some(current) = TASK_MANAGER.lock().current_task() else {
+        return error(ESRCH);
+    };
+    if current.id() == tid {
+        return error(EDEADLK);
+    }
+    let process = current.process().clone();
+    drop(current);
+
+    let mut result = Ok(None);
+    process.thread_exit().wait_until(|| {
+        result = TASK_MANAGER.lock().join(&process, tid);
+        !matches!(result, Ok(None))
+    });
+    match result {
+        Ok(Some(exit_value)) => {
+            if !value.is_null() && value.write(&exit_value).is_err() {
+                return error(EFAULT);
+            }
+            0
+        }
+        // killed while waiting, it exits on its way out
+        Ok(None) => error(EINTR),
+        Err(JoinError::NoThread) => error(ESRCH),
+    }
+}
+
+// sets or reads the fs base of the calling thread, its thread pointer for tls
+fn syscall_arch_prctl(code: usize, addr: usize) -> usize {
+    let Some(current) = TASK_MANAGER.lock().current_task() else {
+        return error(ESRCH);
+    };
+    match code {
+        ARCH_SET_FS => {
+            if addr >= USER_REGION_END {
+                return error(EPERM);
+            }
+            current.set_fs_base(addr as u64);
+            unsafe { set_thread_pointer(addr as u64) };
+            0
+        }
+        ARCH_GET_FS => match UserPtr::<u64>::new(addr).write(&current.fs_base()) {
+            Ok(()) => 0,
+            Err(_) => error(EFAULT),
+        },
+        _ => error(EINVAL),
+    }
+}
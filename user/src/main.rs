@@ -1,7 +1,12 @@
 #![no_std]
 #![no_main]
 
-use core::{arch::asm, panic::PanicInfo};
+use core::{
+    arch::{asm, global_asm},
+    ffi::{CStr, c_char},
+    panic::PanicInfo,
+    ptr::null,
+};
 
 fn write(fp: usize, data: &[u8]) -> usize {
     let mut result: usize = 1;
@@ -27,26 +32,250 @@ fn getpid() -> usize {
     result
 }
 
-fn spawn() -> usize {
+fn spawn(path: &CStr, argv: &[*const c_char]) -> usize {
     let mut result: usize = 4;
+    unsafe {
+        asm!(
+            "syscall",
+            inout("rax") result,
+            in("rdi") path.as_ptr(),
+            in("rsi") argv.as_ptr(),
+            in("rdx") 0,
+            out("rcx") _,
+            out("r11") _,
+        )
+    }
+    result
+}
+
+fn execve(path: &CStr, argv: &[*const c_char]) -> usize {
+    let mut result: usize = 22;
+    unsafe {
+        asm!(
+            "syscall",
+            inout("rax") result,
+            in("rdi") path.as_ptr(),
+            in("rsi") argv.as_ptr(),
+            in("rdx") 0,
+            out("rcx") _,
+            out("r11") _,
+        )
+    }
+    result
+}
+
+fn fork() -> usize {
+    let mut result: usize = 5;
     unsafe {
         asm!("syscall", inout("rax") result, out("rcx") _, out("r11") _);
     }
     result
 }
 
+const PROT_READ: usize = 0x1;
+const PROT_WRITE: usize = 0x2;
+const MAP_PRIVATE: usize = 0x2;
+const MAP_ANONYMOUS: usize = 0x20;
+
+fn mmap(len: usize, prot: usize) -> usize {
+    let mut result: usize = 6;
+    unsafe {
+        asm!(
+            "syscall",
+            inout("rax") result,
+            in("rdi") 0,
+            in("rsi") len,
+            in("rdx") prot,
+            in("r10") MAP_PRIVATE | MAP_ANONYMOUS,
+            out("rcx") _,
+            out("r11") _,
+        )
+    }
+    result
+}
+
+fn mprotect(addr: usize, len: usize, prot: usize) -> usize {
+    let mut result: usize = 8;
+    unsafe {
+        asm!(
+            "syscall",
+            inout("rax") result,
+            in("rdi") addr,
+            in("rsi") len,
+            in("rdx") prot,
+            out("rcx") _,
+            out("r11") _,
+        )
+    }
+    result
+}
+
+fn exit(code: usize) -> ! {
+    unsafe {
+        asm!(
+            "syscall",
+            in("rax") 10,
+            in("rdi") code,
+            options(noreturn)
+        )
+    }
+}
+
+fn waitpid(pid: usize, status: &mut u32) -> usize {
+    let mut result: usize = 11;
+    unsafe {
+        asm!(
+            "syscall",
+            inout("rax") result,
+            in("rdi") pid,
+            in("rsi") status as *mut u32,
+            in("rdx") 0,
+            out("rcx") _,
+            out("r11") _,
+        )
+    }
+    result
+}
+
+#[repr(C)]
+struct Timespec {
+    tv_sec: u64,
+    tv_nsec: u64,
+}
+
+fn nanosleep(duration: &Timespec) -> usize {
+    let mut result: usize = 13;
+    unsafe {
+        asm!(
+            "syscall",
+            inout("rax") result,
+            in("rdi") duration as *const Timespec,
+            out("rcx") _,
+            out("r11") _,
+        )
+    }
+    result
+}
+
+fn thread_create(entry: extern "C" fn(usize) -> !, stack: usize, arg: usize) -> usize {
+    let mut result: usize = 18;
+    unsafe {
+        asm!(
+            "syscall",
+            inout("rax") result,
+            in("rdi") entry as usize,
+            in("rsi") stack,
+            in("rdx") arg,
+            out("rcx") _,
+            out("r11") _,
+        )
+    }
+    result
+}
+
+fn thread_exit(value: usize) -> ! {
+    unsafe {
+        asm!(
+            "syscall",
+            in("rax") 19,
+            in("rdi") value,
+            options(noreturn)
+        )
+    }
+}
+
+fn thread_join(tid: usize, value: &mut usize) -> usize {
+    let mut result: usize = 20;
+    unsafe {
+        asm!(
+            "syscall",
+            inout("rax") result,
+            in("rdi") tid,
+            in("rsi") value as *mut usize,
+            out("rcx") _,
+            out("r11") _,
+        )
+    }
+    result
+}
+
 fn delay() {
-    unsafe { asm!("mov rcx, 0xffffff", "634:", "loop 634b", out("rcx") _) }
+    nanosleep(&Timespec {
+        tv_sec: 0,
+        tv_nsec: 200_000_000,
+    });
+}
+
+static mut THREAD_STACK: [u64; 512] = [0; 512];
+
+extern "C" fn worker(arg: usize) -> ! {
+    write(1, b"thread\n");
+    thread_exit(arg)
 }
 
+// the kernel leaves argc, argv, envp and auxv at the stack pointer
+global_asm!(".global _start", "_start:", "mov rdi, rsp", "call main");
+
+/// # Safety
+///
+/// `stack` has to point at what the kernel left there: argc, then argc pointers to nul
+/// terminated strings.
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn _start() -> ! {
-    let pid = getpid();
-    if pid == 1 {
-        spawn();
+pub unsafe extern "C" fn main(stack: *const usize) -> ! {
+    let argc = unsafe { *stack };
+    let argv = unsafe { stack.add(1) } as *const *const c_char;
+    // started again by execve in a forked child
+    if argc > 1 && unsafe { CStr::from_ptr(*argv.add(1)) } == c"child" {
+        let pid = getpid();
+        for _ in 0..3 {
+            write(1, &[(pid + 48) as u8, b'\n']);
+            delay();
+        }
+        exit(pid);
     }
+
+    if getpid() == 1 {
+        // a thread sharing our address space, running on its own stack
+        let stack = unsafe { (&raw mut THREAD_STACK).add(1) } as usize;
+        let tid = thread_create(worker, stack, 7);
+        let mut value = 0;
+        thread_join(tid, &mut value);
+        write(1, b"joined ");
+        write(1, &[(value + 48) as u8, b'\n']);
+        spawn(c"/init", &[c"/init".as_ptr(), null()]);
+        // a short-lived child, reaped before the parent goes on
+        if fork() == 0 {
+            execve(c"/init", &[c"/init".as_ptr(), c"child".as_ptr(), null()]);
+            exit(1);
+        }
+        let mut status = 0;
+        let child = waitpid(!0, &mut status);
+        write(1, b"reaped ");
+        write(
+            1,
+            &[(child + 48) as u8, b' ', ((status >> 8) as u8) + 48, b'\n'],
+        );
+        // a page that is read-only when forked, the child making it writable must get its own copy
+        let page = mmap(4096, PROT_READ | PROT_WRITE) as *mut u8;
+        unsafe { page.write_volatile(1) };
+        mprotect(page as usize, 4096, PROT_READ);
+        let child = fork();
+        if child == 0 {
+            mprotect(page as usize, 4096, PROT_READ | PROT_WRITE);
+            unsafe { page.write_volatile(2) };
+            exit(0);
+        }
+        waitpid(child, &mut status);
+        if unsafe { page.read_volatile() } == 1 {
+            write(1, b"cow ok\n");
+        } else {
+            write(1, b"cow broken\n");
+        }
+        fork();
+    }
+    let pid = getpid();
     loop {
-        write(1, &[(pid + 48) as u8, '\n' as u8]);
+        write(1, &[(pid + 48) as u8, b'\n']);
         delay();
     }
 }